@@ -7,5 +7,5 @@ _term() {
 }
 
 trap _term SIGTERM SIGINT
-database &
+database --seed /clients.json &
 wait $!
\ No newline at end of file
@@ -12,11 +12,13 @@ _term() {
 }
 
 trap _term SIGTERM SIGINT
-rm -f client_*.db
+rm -f database.db
+rm -f database.wal
+rm -f database.audit
 rm -f log.log
 
 
-cargo run --release --bin database &
+cargo run --release --bin database -- --seed clients.json &
 cargo run --release --bin backend 8000 &
 cargo run --release --bin backend 8001 &
 cargo run --release --bin httpserver 8000 8001  > log.log &
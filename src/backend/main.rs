@@ -1,9 +1,13 @@
 use chrono::{DateTime, Utc};
+use database::codec::{decode_pagina, Decode, DecodeError};
 use database::{
-    BufferOperation, DBconn, Extrato, Operation, OperationKind, Transacao, TransacaoReturn,
-    NCHAR_DESCRIPTION, SIZE_EXTRATO, SIZE_OPERATION, SIZE_TRANSACAO_RETURN,
+    BufferDescription, BufferIdempotencyKey, ClienteReturn, DBconn, Extrato, Filtro, HoldReturn,
+    Motivo, NovoCliente, Operation, OperationKind, Pagina, Rejeicao, SaldoReturn, Status,
+    Transacao, TransacaoLedger, TransacaoReturn, MAX_PAGINA, NCHAR_DESCRIPTION, NTRANSACOES,
+    SIZE_CLIENTE_RETURN, SIZE_EXTRATO, SIZE_HOLD_RETURN, SIZE_IDEMPOTENCY_KEY, SIZE_PAGINA_MAX,
+    SIZE_REJEICOES_MAX, SIZE_SALDO_RETURN, SIZE_TRANSACAO_RETURN,
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
     env,
     net::{SocketAddr, UdpSocket},
@@ -20,6 +24,37 @@ struct TrasacaoBackend {
     descricao: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+struct TransferenciaBackend {
+    de: u32,
+    para: u32,
+    valor: u32,
+    descricao: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LimiteBackend {
+    limite: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ReservaBackend {
+    valor: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CapturaBackend {
+    valor: u32,
+    descricao: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ClienteBackend {
+    limite: i64,
+    #[serde(default)]
+    saldo: i64,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct TrasacaoBackendResponse {
     valor: i64,
@@ -28,17 +63,96 @@ struct TrasacaoBackendResponse {
     realizada_em: String,
 }
 
+impl TrasacaoBackendResponse {
+    fn new(value: i64, description: &BufferDescription, timestap: SystemTime) -> Self {
+        let mut tipo = String::new();
+        let mut mutiplier = 1;
+        if value < 0 {
+            tipo.push('d');
+            mutiplier = -1;
+        } else {
+            tipo.push('c');
+        }
+        let mut descricao = String::new();
+        for ch in description {
+            if *ch == '\0' {
+                break;
+            }
+            descricao.push(*ch);
+        }
+
+        let date: DateTime<Utc> = timestap.into();
+
+        TrasacaoBackendResponse {
+            valor: mutiplier * value,
+            tipo,
+            descricao,
+            realizada_em: date.to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TransacaoPaginaBackend {
+    id: u64,
+    #[serde(flatten)]
+    transacao: TrasacaoBackendResponse,
+    // `id` of the transaction this one reverses.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    estorno_de: Option<u64>,
+    // Id of the other client of a transfer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transferencia: Option<u32>,
+    // Set when the record is a change of the limite.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    novo_limite: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RejeicaoBackend {
+    id: u64,
+    #[serde(flatten)]
+    transacao: TrasacaoBackendResponse,
+    motivo: String,
+    // Id of the client a refused transfer would pay.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transferencia: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RejeicoesBackend {
+    rejeicoes: Vec<RejeicaoBackend>,
+    proximo: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PaginaBackend {
+    transacoes: Vec<TransacaoPaginaBackend>,
+    proximo: Option<u64>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct SaldoBackend {
     total: i64,
     data_extrato: String,
     limite: i64,
+    disponivel: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SaldoEmBackend {
+    saldo: i64,
+    limite: i64,
+    em: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct ExtratoBackend {
     saldo: SaldoBackend,
     ultimas_transacoes: Vec<TrasacaoBackendResponse>,
+    // Only for a filtered extrato, whether more transactions match than the ones listed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mais: Option<bool>,
 }
 
 impl From<Extrato> for ExtratoBackend {
@@ -48,41 +162,23 @@ impl From<Extrato> for ExtratoBackend {
             if !transacao.isvalid {
                 continue;
             }
-            let mut tipo = String::new();
-            let mut mutiplier = 1;
-            if transacao.value < 0 {
-                tipo.push('d');
-                mutiplier = -1;
-            } else {
-                tipo.push('c');
-            }
-            let mut descricao = String::new();
-            for ch in transacao.transacao_description {
-                if ch == '\0' {
-                    break;
-                }
-                descricao.push(ch);
-            }
-
-            let date = transacao.timestap;
-            let date: DateTime<Utc> = date.into();
-
-            transacoes.push(TrasacaoBackendResponse {
-                valor: mutiplier*transacao.value,
-                tipo,
-                descricao,
-                realizada_em: date.to_rfc3339(),
-            });
+            transacoes.push(TrasacaoBackendResponse::new(
+                transacao.value,
+                &{ transacao.transacao_description },
+                transacao.timestap,
+            ));
         }
         let now: DateTime<Utc> = SystemTime::now().into();
         let saldo = SaldoBackend {
             limite: extrato.limite,
             total: extrato.total,
+            disponivel: extrato.disponivel,
             data_extrato: now.to_rfc3339(),
         };
         ExtratoBackend {
             saldo,
             ultimas_transacoes: transacoes,
+            mais: None,
         }
     }
 }
@@ -91,11 +187,22 @@ impl From<Extrato> for ExtratoBackend {
 enum Method {
     Get,
     Post,
+    Patch,
 }
 
 enum Paths {
-    Transacao(u8, TrasacaoBackend),
-    Extrato(u8),
+    Transacao(u32, TrasacaoBackend, BufferIdempotencyKey),
+    Extrato(u32, Filtro),
+    Transacoes(u32, Pagina),
+    CreateClient(ClienteBackend),
+    Estorno(u32, u64),
+    Transferencia(TransferenciaBackend, BufferIdempotencyKey),
+    UpdateLimit(u32, LimiteBackend),
+    Hold(u32, ReservaBackend),
+    Capture(u32, u64, CapturaBackend),
+    Release(u32, u64),
+    Rejeicoes(u32, Pagina),
+    Saldo(u32, u64),
 }
 
 fn send_buffer(socket: &UdpSocket, buffer: &[u8], addr: &SocketAddr) {
@@ -141,7 +248,7 @@ impl Response {
 
         header.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
 
-        let mut out: Vec<u8> = Vec::with_capacity(header.as_bytes().len() + body.len());
+        let mut out: Vec<u8> = Vec::with_capacity(header.len() + body.len());
         out.extend(header.as_bytes());
         out.extend(body);
         out
@@ -180,101 +287,237 @@ fn req_parser(buffer: &[u8]) -> Result<Paths, Response> {
         ));
     }
 
+    let method = match req.method {
+        Some("GET") => Method::Get,
+        Some("POST") => Method::Post,
+        Some("PATCH") => Method::Patch,
+        _ => {
+            return Err(Response::new(
+                405,
+                "Not Found",
+                Some("Method Not Allowed"),
+                None,
+            ))
+        }
+    };
+    let body = &buffer[nbytes..];
+
     let path = req.path.unwrap();
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
     let mut path_iter = path.split('/');
     path_iter.next();
     match (path_iter.next(), path_iter.next(), path_iter.next()) {
-        (Some("clientes"), Some(id), Some(resource)) => {
-            let id = id.parse::<u8>();
+        (Some("clientes"), None | Some(""), None) => {
+            if method != Method::Post {
+                return Err(Response::new(405, "Method Not Allowed", None, None));
+            }
+            let cliente_body: ClienteBackend = body_parser(body)?;
+            if cliente_body.limite < 0 {
+                return Err(Response::new(
+                    422,
+                    "Unprocessable Content",
+                    Some("Invalid limite"),
+                    None,
+                ));
+            }
+            if cliente_body.saldo < -cliente_body.limite {
+                return Err(Response::new(
+                    422,
+                    "Unprocessable Content",
+                    Some("saldo under limite"),
+                    None,
+                ));
+            }
+            Ok(Paths::CreateClient(cliente_body))
+        }
+        (Some("transferencias"), None | Some(""), None) => {
+            if method != Method::Post {
+                return Err(Response::new(405, "Method Not Allowed", None, None));
+            }
+            let transferencia_body: TransferenciaBackend = body_parser(body)?;
+            if transferencia_body.valor == 0 {
+                return Err(Response::new(
+                    422,
+                    "Unprocessable Content",
+                    Some("Invalid valor"),
+                    None,
+                ));
+            }
+            if transferencia_body.descricao.is_empty() {
+                return Err(Response::new(
+                    422,
+                    "Unprocessable Content",
+                    Some("len min descricao"),
+                    None,
+                ));
+            }
+            if transferencia_body.de == transferencia_body.para {
+                return Err(Response::new(
+                    422,
+                    "Unprocessable Content",
+                    Some("de equals para"),
+                    None,
+                ));
+            }
+            let key = idempotency_key_parser(req.headers)?;
+            Ok(Paths::Transferencia(transferencia_body, key))
+        }
+        (Some("clientes"), Some(id), None | Some("")) => {
+            if method != Method::Patch {
+                return Err(Response::new(405, "Method Not Allowed", None, None));
+            }
+            let id = id.parse::<u32>();
             if id.is_err() {
                 return Err(Response::new(404, "Not Found", Some("Invalid ID"), None));
             }
-            let id = id.unwrap();
-            if id >= 6 {
+            let limite_body: LimiteBackend = body_parser(body)?;
+            if limite_body.limite < 0 {
                 return Err(Response::new(
-                    404,
-                    "Not Found",
-                    Some("ID greater than 6"),
+                    422,
+                    "Unprocessable Content",
+                    Some("Invalid limite"),
                     None,
                 ));
             }
-            let method = match req.method {
-                Some("GET") => Method::Get,
-                Some("POST") => Method::Post,
+            Ok(Paths::UpdateLimit(id.unwrap(), limite_body))
+        }
+        (Some("clientes"), Some(id), Some(resource)) => {
+            let id = id.parse::<u32>();
+            if id.is_err() {
+                return Err(Response::new(404, "Not Found", Some("Invalid ID"), None));
+            }
+            let id = id.unwrap();
+            // Only `/clientes/{id}/transacoes/{seq}/estorno` and
+            // `/clientes/{id}/reservas/{reserva}/{captura,liberacao}` go deeper.
+            let action = match (path_iter.next(), path_iter.next(), path_iter.next()) {
+                (None, _, _) | (Some(""), None, _) => None,
+                (Some(item), Some(action), None | Some("")) => Some((item, action)),
                 _ => {
                     return Err(Response::new(
-                        405,
+                        404,
                         "Not Found",
-                        Some("Method Not Allowed"),
+                        Some("Invalid resource"),
                         None,
                     ))
                 }
             };
-            match resource {
-                "transacoes" => {
+            match (resource, action) {
+                ("transacoes", Some((seq, "estorno"))) => {
                     if method != Method::Post {
                         return Err(Response::new(405, "Method Not Allowed", None, None));
                     }
-
-                    let buffer = std::str::from_utf8(buffer[nbytes..].into());
-                    if let Err(e) = buffer {
-                        eprintln!("Backend: could not parse: {e}");
+                    let seq = seq.parse::<u64>();
+                    if !matches!(seq, Ok(1..)) {
                         return Err(Response::new(
-                            422,
-                            "Unprocessable Content ",
-                            Some("Invalid UTF8 char"),
+                            404,
+                            "Not Found",
+                            Some("Invalid transacao"),
                             None,
                         ));
                     }
-                    let buffer = buffer.unwrap();
-
-                    //  BEGIN OF SERDE WORKAROUND
-                    //      Serde does not allow buffers with trailling chars.
-                    let mut sbuffer = String::new();
-                    for ch in buffer.chars() {
-                        if ch == '\0' {
-                            break;
-                        }
-                        sbuffer.push(ch);
+                    Ok(Paths::Estorno(id, seq.unwrap()))
+                }
+                ("transacoes", None) if method == Method::Get => {
+                    Ok(Paths::Transacoes(id, pagina_parser(query)?))
+                }
+                ("transacoes", None) => {
+                    if method != Method::Post {
+                        return Err(Response::new(405, "Method Not Allowed", None, None));
                     }
-                    sbuffer.shrink_to_fit();
-                    //  END OF SERDE WORKAROUND
 
-                    let transacao_body = serde_json::from_str(&sbuffer);
-                    if let Err(e) = transacao_body {
-                        eprintln!("Backend: Invalid json: {e}");
+                    let transacao_body: TrasacaoBackend = body_parser(body)?;
+                    if transacao_body.descricao.is_empty() {
                         return Err(Response::new(
                             422,
                             "Unprocessable Content",
-                            Some("Invalid json"),
+                            Some("len min descricao"),
                             None,
                         ));
                     }
-                    let transacao_body: TrasacaoBackend = transacao_body.unwrap();
-                    if transacao_body.descricao.len() < 1 {
+                    if transacao_body.tipo.len() != 1 {
                         return Err(Response::new(
                             422,
                             "Unprocessable Content",
-                            Some("len min descricao"),
+                            Some("len tipo"),
                             None,
                         ));
                     }
-                    if transacao_body.tipo.len() != 1 {
+
+                    let key = idempotency_key_parser(req.headers)?;
+                    Ok(Paths::Transacao(id, transacao_body, key))
+                }
+                ("extrato", None) => {
+                    if method != Method::Get {
+                        return Err(Response::new(405, "Method Not Allowed", None, None));
+                    }
+                    Ok(Paths::Extrato(id, filtro_parser(query)?))
+                }
+                ("saldo", None) => {
+                    if method != Method::Get {
+                        return Err(Response::new(405, "Method Not Allowed", None, None));
+                    }
+                    let em = instante_parser(query, "em")?;
+                    if em.is_none() {
+                        return Err(Response::new(400, "Bad Request", Some("Missing em"), None));
+                    }
+                    Ok(Paths::Saldo(id, em.unwrap()))
+                }
+                ("rejeicoes", None) => {
+                    if method != Method::Get {
+                        return Err(Response::new(405, "Method Not Allowed", None, None));
+                    }
+                    Ok(Paths::Rejeicoes(id, pagina_parser(query)?))
+                }
+                ("reservas", None) => {
+                    if method != Method::Post {
+                        return Err(Response::new(405, "Method Not Allowed", None, None));
+                    }
+                    let reserva_body: ReservaBackend = body_parser(body)?;
+                    if reserva_body.valor == 0 {
                         return Err(Response::new(
                             422,
                             "Unprocessable Content",
-                            Some("len tipo"),
+                            Some("Invalid valor"),
                             None,
                         ));
                     }
-
-                    Ok(Paths::Transacao(id, transacao_body))
+                    Ok(Paths::Hold(id, reserva_body))
                 }
-                "extrato" => {
-                    if method != Method::Get {
+                ("reservas", Some((reserva, action @ ("captura" | "liberacao")))) => {
+                    if method != Method::Post {
                         return Err(Response::new(405, "Method Not Allowed", None, None));
                     }
-                    Ok(Paths::Extrato(id))
+                    let reserva = reserva.parse::<u64>();
+                    if !matches!(reserva, Ok(1..)) {
+                        return Err(Response::new(
+                            404,
+                            "Not Found",
+                            Some("Invalid reserva"),
+                            None,
+                        ));
+                    }
+                    let reserva = reserva.unwrap();
+                    if action == "liberacao" {
+                        return Ok(Paths::Release(id, reserva));
+                    }
+                    let captura_body: CapturaBackend = body_parser(body)?;
+                    if captura_body.valor == 0 {
+                        return Err(Response::new(
+                            422,
+                            "Unprocessable Content",
+                            Some("Invalid valor"),
+                            None,
+                        ));
+                    }
+                    if captura_body.descricao.is_empty() {
+                        return Err(Response::new(
+                            422,
+                            "Unprocessable Content",
+                            Some("len min descricao"),
+                            None,
+                        ));
+                    }
+                    Ok(Paths::Capture(id, reserva, captura_body))
                 }
                 _ => Err(Response::new(
                     404,
@@ -288,7 +531,161 @@ fn req_parser(buffer: &[u8]) -> Result<Paths, Response> {
     }
 }
 
-fn process_transacao(transacao: TrasacaoBackend, id: u8) -> Response {
+// The optional `Idempotency-Key` header, zeros when it is missing.
+fn idempotency_key_parser(headers: &[httparse::Header]) -> Result<BufferIdempotencyKey, Response> {
+    let mut key = [0; SIZE_IDEMPOTENCY_KEY];
+    let header = headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("Idempotency-Key"));
+    if header.is_none() {
+        return Ok(key);
+    }
+    let value = header.unwrap().value;
+    if value.is_empty() || value.len() > SIZE_IDEMPOTENCY_KEY || value.contains(&0) {
+        return Err(Response::new(
+            400,
+            "Bad Request",
+            Some("Invalid Idempotency-Key"),
+            None,
+        ));
+    }
+    key[..value.len()].copy_from_slice(value);
+    Ok(key)
+}
+
+fn body_parser<T: DeserializeOwned>(body: &[u8]) -> Result<T, Response> {
+    let buffer = std::str::from_utf8(body);
+    if let Err(e) = buffer {
+        eprintln!("Backend: could not parse: {e}");
+        return Err(Response::new(
+            422,
+            "Unprocessable Content ",
+            Some("Invalid UTF8 char"),
+            None,
+        ));
+    }
+    let buffer = buffer.unwrap();
+
+    //  BEGIN OF SERDE WORKAROUND
+    //      Serde does not allow buffers with trailling chars.
+    let mut sbuffer = String::new();
+    for ch in buffer.chars() {
+        if ch == '\0' {
+            break;
+        }
+        sbuffer.push(ch);
+    }
+    sbuffer.shrink_to_fit();
+    //  END OF SERDE WORKAROUND
+
+    let parsed = serde_json::from_str(&sbuffer);
+    if let Err(e) = parsed {
+        eprintln!("Backend: Invalid json: {e}");
+        return Err(Response::new(
+            422,
+            "Unprocessable Content",
+            Some("Invalid json"),
+            None,
+        ));
+    }
+    Ok(parsed.unwrap())
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn pagina_parser(query: &str) -> Result<Pagina, Response> {
+    let limit = match query_param(query, "limit") {
+        None => NTRANSACOES as u32,
+        Some(limit) => match limit.parse::<u32>() {
+            Ok(limit) if limit >= 1 && limit as usize <= MAX_PAGINA => limit,
+            _ => {
+                return Err(Response::new(
+                    400,
+                    "Bad Request",
+                    Some("Invalid limit"),
+                    None,
+                ))
+            }
+        },
+    };
+    let before = match query_param(query, "before") {
+        None => 0,
+        Some(before) => match before.parse::<u64>() {
+            Ok(before) if before >= 1 => before,
+            _ => {
+                return Err(Response::new(
+                    400,
+                    "Bad Request",
+                    Some("Invalid before"),
+                    None,
+                ))
+            }
+        },
+    };
+    Ok(Pagina { limit, before })
+}
+
+// `from` and `to` are RFC 3339 timestamps, both included. Without any of the three
+// parameters the extrato is the usual one.
+fn filtro_parser(query: &str) -> Result<Filtro, Response> {
+    let de = instante_parser(query, "from")?;
+    let ate = instante_parser(query, "to")?;
+    let tipo = match query_param(query, "tipo") {
+        None => 0,
+        Some("c") => b'c',
+        Some("d") => b'd',
+        Some(_) => {
+            return Err(Response::new(
+                400,
+                "Bad Request",
+                Some("Invalid tipo"),
+                None,
+            ))
+        }
+    };
+    Ok(Filtro {
+        ativo: de.is_some() || ate.is_some() || tipo != 0,
+        tipo,
+        de: de.unwrap_or(0),
+        ate: ate.unwrap_or(u64::MAX),
+    })
+}
+
+// Nanoseconds since the epoch, out of range dates are clamped.
+fn instante_parser(query: &str, name: &str) -> Result<Option<u64>, Response> {
+    let value = query_param(query, name);
+    if value.is_none() {
+        return Ok(None);
+    }
+    let date = DateTime::parse_from_rfc3339(value.unwrap());
+    if date.is_err() {
+        return Err(Response::new(
+            400,
+            "Bad Request",
+            Some("Invalid timestamp"),
+            None,
+        ));
+    }
+    let date = date.unwrap();
+    let nanos = match date.timestamp_nanos_opt() {
+        Some(nanos) => nanos.max(0) as u64,
+        None if date.timestamp() < 0 => 0,
+        None => u64::MAX,
+    };
+    Ok(Some(nanos))
+}
+
+fn process_transacao(
+    transacao: TrasacaoBackend,
+    id: u32,
+    idempotency_key: BufferIdempotencyKey,
+) -> Response {
     if transacao.descricao.chars().count() > NCHAR_DESCRIPTION {
         return Response::new(
             422,
@@ -314,7 +711,6 @@ fn process_transacao(transacao: TrasacaoBackend, id: u8) -> Response {
     };
 
     let timestamp = SystemTime::now();
-    let timestamp = timestamp;
     let mut op = Operation {
         kind: OperationKind::Transacao,
         id,
@@ -323,156 +719,446 @@ fn process_transacao(transacao: TrasacaoBackend, id: u8) -> Response {
             transacao_description: ['\0'; NCHAR_DESCRIPTION],
             timestap: timestamp,
         },
+        idempotency_key,
+        ..unsafe { std::mem::zeroed() }
     };
     for (indx, char) in transacao.descricao.chars().enumerate() {
         op.transacao.transacao_description[indx] = char;
     }
 
-    let op_ptr = unsafe { &*(&op as *const _ as *const BufferOperation) };
-    let mut db = DBconn::new();
-    for _ in 0..3 {
-        if let Ok(_) = db {
-            break;
-        }
-        db = DBconn::new();
+    let mut buff = [0; SIZE_TRANSACAO_RETURN];
+    let nbytes = db_request(&op, &mut buff);
+    if let Err(response) = nbytes {
+        return response;
     }
-    if db.is_err() {
-        return Response::new(500, "Internal Error", Some("Cannot connect to DB"), None);
+    let tret = decode_reply(TransacaoReturn::from_bytes(&buff[..nbytes.unwrap()]));
+    if let Err(response) = tret {
+        return response;
     }
-    let db = db.unwrap();
-    let dbconn = db.dbconn();
-    match dbconn.send(op_ptr) {
-        Ok(nbytes) => {
-            if nbytes != SIZE_OPERATION {
-                eprintln!("Backend: DB send error: Send {nbytes} instead of {SIZE_OPERATION}");
-                return Response::new(
-                    500,
-                    "Internal Error",
-                    Some("Backend send wrong bytes"),
-                    None,
-                );
-            }
-        }
-        Err(e) => {
-            eprintln!("Backend: DB send error: {}", e);
-            return Response::new(500, "Internal Error", Some("Backend send"), None);
-        }
+    let tret = tret.unwrap();
+    json_response(&tret)
+}
+
+fn process_transferencia(
+    transferencia: TransferenciaBackend,
+    idempotency_key: BufferIdempotencyKey,
+) -> Response {
+    if transferencia.descricao.chars().count() > NCHAR_DESCRIPTION {
+        return Response::new(
+            422,
+            "Unprocessable Content",
+            Some("Invalid descricao (> 10). Is there graphemes?"),
+            None,
+        );
+    }
+
+    let mut op = Operation {
+        kind: OperationKind::Transferencia,
+        id: transferencia.de,
+        para: transferencia.para,
+        transacao: Transacao {
+            value: transferencia.valor as i64,
+            transacao_description: ['\0'; NCHAR_DESCRIPTION],
+            timestap: SystemTime::now(),
+        },
+        idempotency_key,
+        ..unsafe { std::mem::zeroed() }
     };
+    for (indx, char) in transferencia.descricao.chars().enumerate() {
+        op.transacao.transacao_description[indx] = char;
+    }
+
     let mut buff = [0; SIZE_TRANSACAO_RETURN];
-    let res = dbconn.recv(&mut buff);
-    match res {
-        Err(e) => {
-            eprintln!("Backend: recv error: {}", e);
-            Response::new(500, "Internal Error", Some("Backend recv"), None)
-        }
-        Ok(nbytes) => {
-            if nbytes == 1 {
-                return Response::new(
-                    422,
-                    "Unprocessable Content",
-                    Some("Backend DB inform"),
-                    None,
-                );
-            }
-            if nbytes != SIZE_TRANSACAO_RETURN {
-                return Response::new(
-                    500,
-                    "Internal Error",
-                    Some("Backend DB returns invalid"),
-                    None,
-                );
-            }
+    let nbytes = db_request(&op, &mut buff);
+    if let Err(response) = nbytes {
+        return response;
+    }
+    let tret = decode_reply(TransacaoReturn::from_bytes(&buff[..nbytes.unwrap()]));
+    if let Err(response) = tret {
+        return response;
+    }
+    let tret = tret.unwrap();
+    json_response(&tret)
+}
 
-            let tret = unsafe { *(&buff as *const _ as *const TransacaoReturn) };
-            let sret = serde_json::to_string(&tret);
-            if let Err(e) = sret {
-                eprintln!("Backend: could not convert db response: {e}");
-                return Response::new(
-                    500,
-                    "Internal Error",
-                    Some("Backend convert DB returns error"),
-                    None,
-                );
-            }
-            let mut sret = sret.unwrap();
-            sret.shrink_to_fit();
+fn process_update_limit(id: u32, limite: LimiteBackend) -> Response {
+    let op = Operation {
+        kind: OperationKind::UpdateLimit,
+        id,
+        limite: limite.limite,
+        ..unsafe { std::mem::zeroed() }
+    };
+    let mut buff = [0; SIZE_CLIENTE_RETURN];
+    let nbytes = db_request(&op, &mut buff);
+    if let Err(response) = nbytes {
+        return response;
+    }
+    let cret = decode_reply(ClienteReturn::from_bytes(&buff[..nbytes.unwrap()]));
+    if let Err(response) = cret {
+        return response;
+    }
+    let cret = cret.unwrap();
+    json_response(&cret)
+}
 
-            Response::new(200, "OK", None, Some(sret.as_bytes().into()))
-        }
+fn process_hold(id: u32, reserva: ReservaBackend) -> Response {
+    let mut op = Operation {
+        kind: OperationKind::Hold,
+        id,
+        ..unsafe { std::mem::zeroed() }
+    };
+    op.transacao.value = reserva.valor as i64;
+    let mut buff = [0; SIZE_HOLD_RETURN];
+    let nbytes = db_request(&op, &mut buff);
+    if let Err(response) = nbytes {
+        return response;
+    }
+    let hret = decode_reply(HoldReturn::from_bytes(&buff[..nbytes.unwrap()]));
+    if let Err(response) = hret {
+        return response;
+    }
+    let hret = hret.unwrap();
+    let mut response = json_response(&hret);
+    if response.code == 200 {
+        response.code = 201;
+        response.msg = "Created";
+    }
+    response
+}
+
+fn process_capture(id: u32, reserva: u64, captura: CapturaBackend) -> Response {
+    if captura.descricao.chars().count() > NCHAR_DESCRIPTION {
+        return Response::new(
+            422,
+            "Unprocessable Content",
+            Some("Invalid descricao (> 10). Is there graphemes?"),
+            None,
+        );
+    }
+
+    let mut op = Operation {
+        kind: OperationKind::Capture,
+        id,
+        reserva,
+        transacao: Transacao {
+            value: captura.valor as i64,
+            transacao_description: ['\0'; NCHAR_DESCRIPTION],
+            timestap: SystemTime::now(),
+        },
+        ..unsafe { std::mem::zeroed() }
+    };
+    for (indx, char) in captura.descricao.chars().enumerate() {
+        op.transacao.transacao_description[indx] = char;
+    }
+    process_close_hold(&op)
+}
+
+fn process_release(id: u32, reserva: u64) -> Response {
+    let op = Operation {
+        kind: OperationKind::Release,
+        id,
+        reserva,
+        ..unsafe { std::mem::zeroed() }
+    };
+    process_close_hold(&op)
+}
+
+// A capture and a release get the same reply, the balances once the hold is gone.
+fn process_close_hold(op: &Operation) -> Response {
+    let mut buff = [0; SIZE_TRANSACAO_RETURN];
+    let nbytes = db_request(op, &mut buff);
+    if let Err(response) = nbytes {
+        return response;
+    }
+    let tret = decode_reply(TransacaoReturn::from_bytes(&buff[..nbytes.unwrap()]));
+    if let Err(response) = tret {
+        return response;
+    }
+    let tret = tret.unwrap();
+    json_response(&tret)
+}
+
+fn process_estorno(id: u32, seq: u64) -> Response {
+    let op = Operation {
+        kind: OperationKind::Estorno,
+        id,
+        estorno: seq,
+        ..unsafe { std::mem::zeroed() }
+    };
+    let mut buff = [0; SIZE_TRANSACAO_RETURN];
+    let nbytes = db_request(&op, &mut buff);
+    if let Err(response) = nbytes {
+        return response;
+    }
+    let tret = decode_reply(TransacaoReturn::from_bytes(&buff[..nbytes.unwrap()]));
+    if let Err(response) = tret {
+        return response;
     }
+    let tret = tret.unwrap();
+    json_response(&tret)
 }
 
-fn process_extrato(id: u8) -> Response {
+fn process_extrato(id: u32, filtro: Filtro) -> Response {
     let op = Operation {
         kind: OperationKind::Extrato,
         id,
-        transacao: unsafe { std::mem::zeroed() },
+        filtro,
+        ..unsafe { std::mem::zeroed() }
     };
-    let op_ptr = unsafe { &*(&op as *const _ as *const BufferOperation) };
+    let mut buff = [0; SIZE_EXTRATO];
+    let nbytes = db_request(&op, &mut buff);
+    if let Err(response) = nbytes {
+        return response;
+    }
+    let tret = decode_reply(Extrato::from_bytes(&buff[..nbytes.unwrap()]));
+    if let Err(response) = tret {
+        return response;
+    }
+    let tret = tret.unwrap();
+    let mut extrato = ExtratoBackend::from(tret);
+    if filtro.ativo {
+        extrato.mais = Some(tret.mais);
+    }
+    json_response(&extrato)
+}
+
+fn process_transacoes(id: u32, pagina: Pagina) -> Response {
+    let op = Operation {
+        kind: OperationKind::Transacoes,
+        id,
+        pagina,
+        ..unsafe { std::mem::zeroed() }
+    };
+    let mut buff = [0; SIZE_PAGINA_MAX];
+    let nbytes = db_request(&op, &mut buff);
+    if let Err(response) = nbytes {
+        return response;
+    }
+    let page = decode_reply(decode_pagina::<TransacaoLedger>(&buff[..nbytes.unwrap()]));
+    if let Err(response) = page {
+        return response;
+    }
+    let (header, page) = page.unwrap();
+
+    let mut transacoes = Vec::with_capacity(page.len());
+    for transacao in page {
+        transacoes.push(TransacaoPaginaBackend {
+            id: transacao.seq,
+            transacao: TrasacaoBackendResponse::new(
+                transacao.value,
+                &{ transacao.transacao_description },
+                transacao.timestap,
+            ),
+            estorno_de: match transacao.estorno {
+                0 => None,
+                estorno => Some(estorno),
+            },
+            transferencia: match transacao.transferencia {
+                0 => None,
+                transferencia => Some(transferencia),
+            },
+            novo_limite: Some(transacao.limite).filter(|_| transacao.update_limit),
+        });
+    }
+    let proximo = match header.next_before {
+        0 => None,
+        next_before => Some(next_before),
+    };
+    json_response(&PaginaBackend {
+        transacoes,
+        proximo,
+    })
+}
+
+fn process_saldo(id: u32, em: u64) -> Response {
+    let op = Operation {
+        kind: OperationKind::Saldo,
+        id,
+        em,
+        ..unsafe { std::mem::zeroed() }
+    };
+    let mut buff = [0; SIZE_SALDO_RETURN];
+    let nbytes = db_request(&op, &mut buff);
+    if let Err(response) = nbytes {
+        return response;
+    }
+    let sret = decode_reply(SaldoReturn::from_bytes(&buff[..nbytes.unwrap()]));
+    if let Err(response) = sret {
+        return response;
+    }
+    let sret = sret.unwrap();
+    let em: DateTime<Utc> = DateTime::from_timestamp_nanos(em.min(i64::MAX as u64) as i64);
+    json_response(&SaldoEmBackend {
+        saldo: sret.saldo,
+        limite: sret.limite,
+        em: em.to_rfc3339(),
+    })
+}
+
+fn process_rejeicoes(id: u32, pagina: Pagina) -> Response {
+    let op = Operation {
+        kind: OperationKind::Rejeicoes,
+        id,
+        pagina,
+        ..unsafe { std::mem::zeroed() }
+    };
+    let mut buff = [0; SIZE_REJEICOES_MAX];
+    let nbytes = db_request(&op, &mut buff);
+    if let Err(response) = nbytes {
+        return response;
+    }
+    let page = decode_reply(decode_pagina::<Rejeicao>(&buff[..nbytes.unwrap()]));
+    if let Err(response) = page {
+        return response;
+    }
+    let (header, page) = page.unwrap();
+
+    let mut rejeicoes = Vec::with_capacity(page.len());
+    for rejeicao in page {
+        let motivo = match rejeicao.motivo {
+            Motivo::Limite => "limite",
+            Motivo::Reservas => "reservas",
+        };
+        rejeicoes.push(RejeicaoBackend {
+            id: rejeicao.seq,
+            transacao: TrasacaoBackendResponse::new(
+                rejeicao.value,
+                &{ rejeicao.transacao_description },
+                rejeicao.timestap,
+            ),
+            motivo: motivo.to_string(),
+            transferencia: match rejeicao.transferencia {
+                0 => None,
+                transferencia => Some(transferencia),
+            },
+        });
+    }
+    let proximo = match header.next_before {
+        0 => None,
+        next_before => Some(next_before),
+    };
+    json_response(&RejeicoesBackend { rejeicoes, proximo })
+}
+
+fn process_create_client(cliente: ClienteBackend) -> Response {
+    let op = Operation {
+        kind: OperationKind::CreateClient,
+        id: 0,
+        novo_cliente: NovoCliente {
+            limite: cliente.limite,
+            saldo: cliente.saldo,
+        },
+        ..unsafe { std::mem::zeroed() }
+    };
+    let mut buff = [0; SIZE_CLIENTE_RETURN];
+    let nbytes = db_request(&op, &mut buff);
+    if let Err(response) = nbytes {
+        return response;
+    }
+    let cret = decode_reply(ClienteReturn::from_bytes(&buff[..nbytes.unwrap()]));
+    if let Err(response) = cret {
+        return response;
+    }
+    let cret = cret.unwrap();
+    let mut response = json_response(&cret);
+    if response.code == 200 {
+        response.code = 201;
+        response.msg = "Created";
+    }
+    response
+}
+
+fn json_response<T: Serialize>(value: &T) -> Response {
+    let sret = serde_json::to_string(value);
+    if let Err(e) = sret {
+        eprintln!("Backend: could not convert db response: {e}");
+        return Response::new(
+            500,
+            "Internal Error",
+            Some("Backend convert DB returns error"),
+            None,
+        );
+    }
+    let mut sret = sret.unwrap();
+    sret.shrink_to_fit();
+    Response::new(200, "OK", None, Some(sret.as_bytes().into()))
+}
+
+// Sends `op` to the DB, retransmitting it when no reply comes, and reads the payload of
+// the reply into `buff`, returning its size. Any status but `Status::Ok` becomes the response.
+fn db_request(op: &Operation, buff: &mut [u8]) -> Result<usize, Response> {
     let mut db = DBconn::new();
     for _ in 0..3 {
-        if let Ok(_) = db {
+        if db.is_ok() {
             break;
         }
         db = DBconn::new();
     }
     if db.is_err() {
-        return Response::new(500, "Internal Error", Some("Cannot connect to DB"), None);
+        return Err(Response::new(
+            500,
+            "Internal Error",
+            Some("Cannot connect to DB"),
+            None,
+        ));
     }
     let db = db.unwrap();
-    let dbconn = db.dbconn();
-    match dbconn.send(op_ptr) {
-        Ok(nbytes) => {
-            if nbytes != SIZE_OPERATION {
-                eprintln!("Backend: DB send error: Send {nbytes} instead of {SIZE_OPERATION}");
-                return Response::new(
-                    500,
-                    "Internal Error",
-                    Some("Backend send wrong bytes"),
-                    None,
-                );
-            }
-        }
-        Err(e) => {
-            eprintln!("Backend: DB send error: {}", e);
-            return Response::new(500, "Internal Error", Some("Backend send"), None);
-        }
-    };
-    let mut buff = [0; SIZE_EXTRATO];
-    match dbconn.recv(&mut buff) {
-        Err(e) => {
-            eprintln!("Backend: recv error: {}", e);
-            Response::new(500, "Internal Error", Some("Backend recv"), None)
+    let reply = db.request(op, buff);
+    if let Err(e) = reply {
+        eprintln!("Backend: DB request error: {}", e);
+        if e.kind() == std::io::ErrorKind::TimedOut {
+            return Err(Response::new(
+                504,
+                "Gateway Timeout",
+                Some("Backend DB timeout"),
+                None,
+            ));
         }
-        Ok(nbytes) => {
-            if nbytes == 1 {
-                return Response::new(404, "Not Found", None, None);
-            }
-            if nbytes != SIZE_EXTRATO {
-                return Response::new(
-                    500,
-                    "Internal Error",
-                    Some("Backend DB returns invalid"),
-                    None,
-                );
-            }
+        return Err(Response::new(
+            500,
+            "Internal Error",
+            Some("Backend request"),
+            None,
+        ));
+    }
+    let (status, nbytes) = reply.unwrap();
+    if status != Status::Ok {
+        return Err(status_response(status));
+    }
+    Ok(nbytes)
+}
 
-            let tret = unsafe { *(&buff as *const _ as *const Extrato) };
-            let extrato = ExtratoBackend::from(tret);
-            let sret = serde_json::to_string(&extrato);
-            if let Err(e) = sret {
-                eprintln!("Backend: could not convert db response: {e}");
-                return Response::new(
-                    500,
-                    "Internal Error",
-                    Some("Backend convert DB returns error"),
-                    None,
-                );
-            }
-            let mut sret = sret.unwrap();
-            sret.shrink_to_fit();
-            Response::new(200, "OK", None, Some(sret.as_bytes().into()))
+// A reply of the DB that does not decode is answered with a 500.
+fn decode_reply<T>(decoded: Result<T, DecodeError>) -> Result<T, Response> {
+    decoded.map_err(|e| {
+        eprintln!("Backend: could not decode db response: {e}");
+        Response::new(
+            500,
+            "Internal Error",
+            Some("Backend DB returns invalid"),
+            None,
+        )
+    })
+}
+
+fn status_response(status: Status) -> Response {
+    match status {
+        Status::Ok => Response::new(200, "OK", None, None),
+        Status::NotFound => Response::new(404, "Not Found", None, None),
+        Status::LimitExceeded => Response::new(
+            422,
+            "Unprocessable Content",
+            Some("Backend DB inform"),
+            None,
+        ),
+        Status::Malformed => Response::new(400, "Bad Request", Some("Backend DB inform"), None),
+        Status::StorageError => {
+            Response::new(500, "Internal Error", Some("Backend DB storage"), None)
         }
+        Status::Busy => Response::new(503, "Service Unavailable", Some("Backend DB busy"), None),
+        // A transaction already reversed, a reused idempotency key, a client with every hold
+        // slot taken, no id left for a new client.
+        Status::Conflict => Response::new(409, "Conflict", Some("Backend DB inform"), None),
     }
 }
 
@@ -509,8 +1195,18 @@ fn main() -> std::io::Result<()> {
         }
         let path = path.unwrap();
         let response: Response = match path {
-            Paths::Transacao(id, transacao) => process_transacao(transacao, id),
-            Paths::Extrato(id) => process_extrato(id),
+            Paths::Transacao(id, transacao, key) => process_transacao(transacao, id, key),
+            Paths::Extrato(id, filtro) => process_extrato(id, filtro),
+            Paths::Transacoes(id, pagina) => process_transacoes(id, pagina),
+            Paths::CreateClient(cliente) => process_create_client(cliente),
+            Paths::Estorno(id, seq) => process_estorno(id, seq),
+            Paths::Transferencia(transferencia, key) => process_transferencia(transferencia, key),
+            Paths::UpdateLimit(id, limite) => process_update_limit(id, limite),
+            Paths::Hold(id, reserva) => process_hold(id, reserva),
+            Paths::Capture(id, reserva, captura) => process_capture(id, reserva, captura),
+            Paths::Release(id, reserva) => process_release(id, reserva),
+            Paths::Rejeicoes(id, pagina) => process_rejeicoes(id, pagina),
+            Paths::Saldo(id, em) => process_saldo(id, em),
         };
         send_buffer(&socket, &response.into_vec(), &addr);
     }
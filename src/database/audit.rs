@@ -0,0 +1,199 @@
+use crate::client::TransacaoRaw;
+use crate::crc::crc32;
+use database::{Motivo, Pagina, PaginaHeader, Rejeicao, Transacao, MAX_PAGINA, NTRANSACOES};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+pub const AUDIT_FILE: &str = "database.audit";
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct AuditRecordRaw {
+    crc: u32,
+    id: u32,
+    transacao: TransacaoRaw,
+    motivo: u32,
+    transferencia: u32,
+}
+const SIZE_AUDIT_RECORD: usize = size_of::<AuditRecordRaw>();
+
+impl AuditRecordRaw {
+    fn checksum(&self) -> u32 {
+        let buff: &[u8; SIZE_AUDIT_RECORD] = unsafe { std::mem::transmute(self) };
+        crc32(&buff[size_of::<u32>()..])
+    }
+}
+
+// Every transaction the DB refused, in the order it refused them. The log is only
+// appended to: it is never truncated nor replicated, each DB keeps what it saw.
+pub struct AuditLog {
+    // None for `StorageKind::Memory`, every record stays in `pending`.
+    file: Option<File>,
+    // Offsets of the records of each client, oldest first.
+    index: HashMap<u32, Vec<u64>>,
+    len: u64,
+    // Written but not flushed yet.
+    pending: Vec<u8>,
+}
+
+impl AuditLog {
+    // A torn or corrupted tail, left by a crash in the middle of an append, is cut off.
+    pub fn open(path: &Path) -> io::Result<AuditLog> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut buff = Vec::new();
+        file.read_to_end(&mut buff)?;
+
+        let mut index: HashMap<u32, Vec<u64>> = HashMap::new();
+        let mut len = 0;
+        for chunk in buff.chunks(SIZE_AUDIT_RECORD) {
+            if chunk.len() != SIZE_AUDIT_RECORD {
+                eprintln!(
+                    "DB.Audit: Ignoring partial record at the end of {}",
+                    path.display()
+                );
+                break;
+            }
+            let raw: AuditRecordRaw = unsafe { std::ptr::read(chunk.as_ptr() as *const _) };
+            if raw.crc != raw.checksum() || raw.motivo > Motivo::Reservas as u32 {
+                eprintln!(
+                    "DB.Audit: Ignoring corrupted record at the end of {}",
+                    path.display()
+                );
+                break;
+            }
+            index.entry(raw.id).or_default().push(len);
+            len += SIZE_AUDIT_RECORD as u64;
+        }
+
+        if len != buff.len() as u64 {
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(len))?;
+        println!(
+            "DB.Audit: {} refusals loaded from {}",
+            len / SIZE_AUDIT_RECORD as u64,
+            path.display()
+        );
+        Ok(AuditLog {
+            file: Some(file),
+            index,
+            len,
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn memory() -> AuditLog {
+        AuditLog {
+            file: None,
+            index: HashMap::new(),
+            len: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    // Only buffers the record, it is durable after the next `flush`.
+    pub fn write(&mut self, id: u32, transacao: &Transacao, motivo: Motivo, transferencia: u32) {
+        let mut raw = AuditRecordRaw {
+            crc: 0,
+            id,
+            transacao: transacao.into(),
+            motivo: motivo as u32,
+            transferencia,
+        };
+        raw.crc = raw.checksum();
+        let buff: &[u8; SIZE_AUDIT_RECORD] = unsafe { std::mem::transmute(&raw) };
+        self.pending.extend_from_slice(buff);
+        self.index.entry(id).or_default().push(self.len);
+        self.len += SIZE_AUDIT_RECORD as u64;
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        file.write_all(&self.pending)?;
+        file.sync_data()?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    // Same paging as the ledger, `seq` counts the refusals of client `id` only.
+    pub fn read_before(
+        &self,
+        id: u32,
+        pagina: Pagina,
+    ) -> io::Result<(PaginaHeader, Vec<Rejeicao>)> {
+        let limit = match pagina.limit as usize {
+            0 => NTRANSACOES,
+            limit => limit.min(MAX_PAGINA),
+        } as u64;
+        let offsets = self.index.get(&id).map_or(&[][..], |offsets| &offsets[..]);
+        let count = offsets.len() as u64;
+        let end = if pagina.before == 0 {
+            count + 1
+        } else {
+            pagina.before.min(count + 1)
+        };
+        let start = end.saturating_sub(limit).max(1);
+
+        let mut rejeicoes = Vec::with_capacity(end.saturating_sub(start) as usize);
+        for seq in (start..end).rev() {
+            rejeicoes.push(self.read(seq, offsets[seq as usize - 1])?);
+        }
+        let header = PaginaHeader {
+            ntransacoes: rejeicoes.len() as u32,
+            next_before: if start > 1 { start } else { 0 },
+        };
+        Ok((header, rejeicoes))
+    }
+
+    fn read(&self, seq: u64, offset: u64) -> io::Result<Rejeicao> {
+        let mut buff = [0; SIZE_AUDIT_RECORD];
+        let flushed = self.len - self.pending.len() as u64;
+        if offset < flushed {
+            // Only a log with a file has anything flushed.
+            self.file
+                .as_ref()
+                .unwrap()
+                .read_exact_at(&mut buff, offset)?;
+        } else {
+            let start = (offset - flushed) as usize;
+            buff.copy_from_slice(&self.pending[start..start + SIZE_AUDIT_RECORD]);
+        }
+        let raw: AuditRecordRaw = unsafe { std::ptr::read(buff.as_ptr() as *const _) };
+        if raw.crc != raw.checksum() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "audit record with a bad checksum",
+            ));
+        }
+        let transacao: Transacao = (&raw.transacao)
+            .try_into()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{e}")))?;
+        let motivo = match raw.motivo {
+            0 => Motivo::Limite,
+            _ => Motivo::Reservas,
+        };
+        Ok(Rejeicao {
+            seq,
+            value: transacao.value,
+            transacao_description: transacao.transacao_description,
+            timestap: transacao.timestap,
+            motivo,
+            transferencia: raw.transferencia,
+        })
+    }
+}
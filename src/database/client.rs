@@ -1,107 +1,939 @@
-use database::{Extrato, Transacao, TransacaoExtrato, NCHAR_DESCRIPTION, NTRANSACOES};
+use crate::crc::crc32;
+use crate::ledger::{Ledger, Link};
+use crate::pager::{DirectoryEntry, Pager, PAGE_SIZE};
+use crate::wal::{Record, Wal};
+use database::{
+    BufferIdempotencyKey, Extrato, Filtro, HoldReturn, Motivo, Pagina, PaginaHeader, SaldoReturn,
+    Transacao, TransacaoExtrato, TransacaoLedger, TransacaoReturn, MAX_PAGINA, NCHAR_DESCRIPTION,
+    NTRANSACOES, SIZE_IDEMPOTENCY_KEY,
+};
 use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
 use std::mem::size_of;
-use std::time::UNIX_EPOCH;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(Debug)]
 pub struct Client {
-    pub id: u8,
+    pub id: u32,
     pub limite: i64,
+    // The limite the client was created with, the ledger only has the changes to it.
+    pub limite_inicial: i64,
     pub saldo: i64,
+    // The saldo the client was created with, before anything in the ledger.
+    pub saldo_inicial: i64,
     pub transacoes: VecDeque<Transacao>,
+    // LSN of the last WAL record applied to this client.
+    pub lsn: u64,
+    pub ledger: Ledger,
+    // Idempotency keys of the last transactions, oldest first.
+    keys: VecDeque<IdempotencyEntry>,
+    // Transactions in the WAL buffer, they go to the ledger once it is flushed.
+    pending_ledger: Vec<(u64, Transacao, Link)>,
+    // Open holds, oldest first, and the last `reserva` given out.
+    holds: Vec<Hold>,
+    last_hold: u64,
+    slots: [u32; 2],
+    version: u64,
+    dirty: bool,
 }
 impl PartialEq for Client {
     fn eq(&self, other: &Self) -> bool {
         self.id == other.id
     }
 }
-impl From<&ClientRaw> for Client {
-    fn from(client: &ClientRaw) -> Self {
-        let mut out = Client {
-            id: client.id,
-            limite: client.limite,
-            saldo: client.saldo,
-            transacoes: VecDeque::new(),
-        };
 
-        for (indx, transacao) in client.transacoes.iter().enumerate() {
-            if indx >= client.ntransacoes {
+#[derive(Debug)]
+pub enum LoadError {
+    Io(std::io::Error),
+    BadMagic([u8; 4]),
+    UnsupportedVersion(u16),
+    BadChecksum { expected: u32, found: u32 },
+    Invalid(&'static str),
+}
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "I/O error: {e}"),
+            LoadError::BadMagic(magic) => write!(f, "bad magic number {magic:?}"),
+            LoadError::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {version}")
+            }
+            LoadError::BadChecksum { expected, found } => {
+                write!(
+                    f,
+                    "bad checksum: expected {expected:#010x}, found {found:#010x}"
+                )
+            }
+            LoadError::Invalid(reason) => write!(f, "invalid data: {reason}"),
+        }
+    }
+}
+
+// Why a reversal was refused.
+#[derive(Debug)]
+pub enum EstornoError {
+    // No transaction with that `seq` in the ledger.
+    NotFound,
+    AlreadyReversed,
+    // A reversal cannot be reversed itself.
+    IsEstorno,
+    // Reversing one side of a transfer would create money, it takes another transfer.
+    IsTransferencia,
+    // A change of the limite is undone by another one.
+    IsUpdateLimit,
+    // Reversing a credit would take the saldo under the limite, the refused reversal goes
+    // to the audit log.
+    Limit(Motivo, Transacao),
+    Io(std::io::Error),
+}
+
+impl Display for EstornoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EstornoError::NotFound => write!(f, "no such transaction"),
+            EstornoError::AlreadyReversed => write!(f, "already reversed"),
+            EstornoError::IsEstorno => write!(f, "it is a reversal"),
+            EstornoError::IsTransferencia => write!(f, "it is a transfer"),
+            EstornoError::IsUpdateLimit => write!(f, "it is a change of the limite"),
+            EstornoError::Limit(..) => write!(f, "saldo would be under the limite"),
+            EstornoError::Io(e) => write!(f, "I/O error: {e}"),
+        }
+    }
+}
+
+// Why a transaction or a transfer was refused.
+#[derive(Debug)]
+pub enum TransacaoError {
+    Limit(Motivo),
+    // The idempotency key was already used by another operation, or with another payload.
+    KeyReused,
+    // A transfer of nothing, or of a negative amount that would pull from `para`.
+    NotPositive,
+}
+
+// Why a capture or a release was refused.
+#[derive(Debug)]
+pub enum HoldError {
+    // No open hold with that `reserva`.
+    NotFound,
+    // Already `MAX_HOLDS` open holds.
+    Full,
+    // The hold, or the capture, would take `disponivel` under the limite. The debit it
+    // stands for goes to the audit log.
+    Limit(Motivo, Transacao),
+    // The amount of the hold or the capture is not positive.
+    NotPositive,
+}
+
+impl Display for HoldError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HoldError::NotFound => write!(f, "no such hold"),
+            HoldError::Full => write!(f, "too many open holds"),
+            HoldError::Limit(..) => write!(f, "disponivel would be under the limite"),
+            HoldError::NotPositive => write!(f, "amount is not positive"),
+        }
+    }
+}
+
+// A client slot page starts with this header, `version` tells which of the two slots is newer.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct SlotHeader {
+    crc: u32,
+    version: u64,
+}
+const SIZE_SLOT_HEADER: usize = size_of::<SlotHeader>();
+
+// On-disk form of a `Transacao`: the description is kept as raw code points and
+// the timestamp as nanoseconds since the epoch, so both can be validated on load.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct TransacaoRaw {
+    value: i64,
+    transacao_description: [u32; NCHAR_DESCRIPTION],
+    timestamp: u64,
+}
+
+impl From<&Transacao> for TransacaoRaw {
+    fn from(transacao: &Transacao) -> Self {
+        let mut transacao_description = [0; NCHAR_DESCRIPTION];
+        for (indx, ch) in { transacao.transacao_description }.iter().enumerate() {
+            transacao_description[indx] = *ch as u32;
+        }
+        let timestamp = { transacao.timestap }
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        TransacaoRaw {
+            value: transacao.value,
+            transacao_description,
+            timestamp,
+        }
+    }
+}
+
+impl TransacaoRaw {
+    // What `fsck` checks, `now` is in nanoseconds since the epoch.
+    pub fn check(&self, now: u64) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.timestamp > now {
+            problems.push(format!("timestamp {} is in the future", { self.timestamp }));
+        }
+        let mut padding = false;
+        for ch in self.transacao_description {
+            if char::from_u32(ch).is_none() {
+                problems.push(format!("description has the invalid code point {ch:#x}"));
+                break;
+            }
+            if padding && ch != 0 {
+                problems.push("description has text after the NUL padding".into());
                 break;
             }
-            out.transacoes.push_back(*transacao)
+            padding |= ch == 0;
         }
+        problems
+    }
+    // Clamps the timestamp to `now` and cuts the description at the first bad code point.
+    pub fn repair(&mut self, now: u64) {
+        if self.timestamp > now {
+            self.timestamp = now;
+        }
+        let mut description = self.transacao_description;
+        let mut padding = false;
+        for ch in description.iter_mut() {
+            padding |= *ch == 0 || char::from_u32(*ch).is_none();
+            if padding {
+                *ch = 0;
+            }
+        }
+        self.transacao_description = description;
+    }
+}
 
-        out
+impl TryFrom<&TransacaoRaw> for Transacao {
+    type Error = LoadError;
+    fn try_from(transacao: &TransacaoRaw) -> Result<Self, Self::Error> {
+        let mut transacao_description = ['\0'; NCHAR_DESCRIPTION];
+        for (indx, ch) in { transacao.transacao_description }.iter().enumerate() {
+            let ch = char::from_u32(*ch);
+            if ch.is_none() {
+                return Err(LoadError::Invalid("description is not valid text"));
+            }
+            transacao_description[indx] = ch.unwrap();
+        }
+        Ok(Transacao {
+            value: transacao.value,
+            transacao_description,
+            timestap: UNIX_EPOCH + Duration::from_nanos(transacao.timestamp),
+        })
+    }
+}
+
+// Number of idempotency keys remembered per client.
+pub const IDEMPOTENCY_KEYS: usize = 32;
+const NO_IDEMPOTENCY_KEY: BufferIdempotencyKey = [0; SIZE_IDEMPOTENCY_KEY];
+// Descriptions of the ledger records pushed by a reversal and a change of the limite,
+// and of a refused hold in the audit log.
+const ESTORNO_DESCRIPTION: &str = "estorno";
+const LIMITE_DESCRIPTION: &str = "limite";
+const RESERVA_DESCRIPTION: &str = "reserva";
+
+fn described(value: i64, description: &str) -> Transacao {
+    let mut transacao = Transacao {
+        value,
+        transacao_description: ['\0'; NCHAR_DESCRIPTION],
+        timestap: SystemTime::now(),
+    };
+    for (indx, ch) in description.chars().enumerate() {
+        transacao.transacao_description[indx] = ch;
+    }
+    transacao
+}
+
+// Number of holds a client can have open at once.
+pub const MAX_HOLDS: usize = 32;
+
+// Funds reserved until they are captured or released.
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+struct Hold {
+    reserva: u64,
+    valor: i64,
+}
+
+fn nanos(transacao: &Transacao) -> u64 {
+    { transacao.timestap }
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn passes(filtro: &Filtro, transacao: &Transacao) -> bool {
+    let timestamp = nanos(transacao);
+    let tipo = match filtro.tipo {
+        b'c' => transacao.value > 0,
+        b'd' => transacao.value < 0,
+        _ => true,
+    };
+    tipo && filtro.de <= timestamp && timestamp <= filtro.ate
+}
+
+// A key, the `fingerprint` of the request that used it and the reply given to it.
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+struct IdempotencyEntry {
+    key: BufferIdempotencyKey,
+    request: u32,
+    ret: TransacaoReturn,
+}
+
+// Tells apart the requests a key can be reused on: `para` is the payee of a transfer, 0 for
+// a transaction, and `transacao` what the request moves for the client, its timestamp aside
+// since a retry gets a new one. A checksum keeps the client inside its slot page.
+fn fingerprint(para: u32, transacao: &Transacao) -> u32 {
+    let mut buff = Vec::with_capacity(4 + 8 + 4 * NCHAR_DESCRIPTION);
+    buff.extend(para.to_le_bytes());
+    buff.extend({ transacao.value }.to_le_bytes());
+    let description = transacao.transacao_description;
+    for ch in description {
+        buff.extend((ch as u32).to_le_bytes());
     }
+    crc32(&buff)
 }
 
 #[derive(Clone, Copy)]
 #[repr(C, packed)]
 pub struct ClientRaw {
-    id: u8,
+    id: u32,
     limite: i64,
+    limite_inicial: i64,
     saldo: i64,
-    ntransacoes: usize,
-    transacoes: [Transacao; NTRANSACOES],
+    saldo_inicial: i64,
+    lsn: u64,
+    ntransacoes: u32,
+    transacoes: [TransacaoRaw; NTRANSACOES],
+    nkeys: u32,
+    keys: [IdempotencyEntry; IDEMPOTENCY_KEYS],
+    last_hold: u64,
+    nholds: u32,
+    holds: [Hold; MAX_HOLDS],
 }
+const SIZE_CLIENT_RAW: usize = size_of::<ClientRaw>();
+const _: () = assert!(SIZE_SLOT_HEADER + SIZE_CLIENT_RAW <= PAGE_SIZE);
 
 impl From<&Client> for ClientRaw {
     fn from(client: &Client) -> Self {
         let mut out = ClientRaw {
             id: client.id,
             limite: client.limite,
+            limite_inicial: client.limite_inicial,
             saldo: client.saldo,
+            saldo_inicial: client.saldo_inicial,
+            lsn: client.lsn,
             transacoes: unsafe { std::mem::zeroed() },
-            ntransacoes: client.transacoes.len(),
+            ntransacoes: client.transacoes.len() as u32,
+            nkeys: client.keys.len() as u32,
+            keys: unsafe { std::mem::zeroed() },
+            last_hold: client.last_hold,
+            nholds: client.holds.len() as u32,
+            holds: unsafe { std::mem::zeroed() },
         };
         for (indx, transacao) in client.transacoes.iter().enumerate() {
-            out.transacoes[indx] = *transacao;
+            out.transacoes[indx] = transacao.into();
+        }
+        for (indx, entry) in client.keys.iter().enumerate() {
+            out.keys[indx] = *entry;
+        }
+        for (indx, hold) in client.holds.iter().enumerate() {
+            out.holds[indx] = *hold;
         }
         out
     }
 }
 
+impl ClientRaw {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+    pub fn lsn(&self) -> u64 {
+        self.lsn
+    }
+    pub fn check(&self, now: u64) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.ntransacoes > NTRANSACOES as u32 {
+            problems.push(format!("{} transactions, more than NTRANSACOES", {
+                self.ntransacoes
+            }));
+        }
+        if self.saldo < -self.limite {
+            problems.push(format!(
+                "saldo {} is under the limite {}",
+                { self.saldo },
+                { self.limite }
+            ));
+        }
+        if self.limite_inicial < 0 {
+            problems.push(format!("limite_inicial {} is negative", {
+                self.limite_inicial
+            }));
+        }
+        if self.nkeys > IDEMPOTENCY_KEYS as u32 {
+            problems.push(format!(
+                "{} idempotency keys, more than IDEMPOTENCY_KEYS",
+                { self.nkeys }
+            ));
+        }
+        if self.nholds > MAX_HOLDS as u32 {
+            problems.push(format!("{} holds, more than MAX_HOLDS", { self.nholds }));
+        }
+        let holds = self.holds;
+        for hold in holds.iter().take((self.nholds as usize).min(MAX_HOLDS)) {
+            if hold.valor <= 0 || hold.reserva > self.last_hold {
+                problems.push(format!("hold {} of {} is invalid", { hold.reserva }, {
+                    hold.valor
+                }));
+            }
+        }
+        let transacoes = self.transacoes;
+        let ntransacoes = (self.ntransacoes as usize).min(NTRANSACOES);
+        for (indx, transacao) in transacoes.iter().take(ntransacoes).enumerate() {
+            for problem in transacao.check(now) {
+                problems.push(format!("transaction {indx}: {problem}"));
+            }
+        }
+        problems
+    }
+    // The saldo is left alone, only an operator can tell which side of the books is wrong.
+    pub fn repair(&mut self, now: u64) {
+        self.ntransacoes = self.ntransacoes.min(NTRANSACOES as u32);
+        self.nkeys = self.nkeys.min(IDEMPOTENCY_KEYS as u32);
+        self.nholds = self.nholds.min(MAX_HOLDS as u32);
+        let mut transacoes = self.transacoes;
+        for transacao in transacoes.iter_mut() {
+            transacao.repair(now);
+        }
+        self.transacoes = transacoes;
+    }
+}
+
 impl Client {
-    pub fn new(id: u8, limite: i64, saldo: i64) -> Client {
-        let client = Client {
+    fn from_raw(
+        client: &ClientRaw,
+        ledger: Ledger,
+        slots: [u32; 2],
+        version: u64,
+    ) -> Result<Client, LoadError> {
+        if client.ntransacoes > NTRANSACOES as u32 {
+            return Err(LoadError::Invalid("more transactions than NTRANSACOES"));
+        }
+        if client.nkeys > IDEMPOTENCY_KEYS as u32 {
+            return Err(LoadError::Invalid(
+                "more idempotency keys than IDEMPOTENCY_KEYS",
+            ));
+        }
+        if client.nholds > MAX_HOLDS as u32 {
+            return Err(LoadError::Invalid("more holds than MAX_HOLDS"));
+        }
+        let mut out = Client {
+            id: client.id,
+            limite: client.limite,
+            limite_inicial: client.limite_inicial,
+            saldo: client.saldo,
+            saldo_inicial: client.saldo_inicial,
+            transacoes: VecDeque::new(),
+            lsn: client.lsn,
+            ledger,
+            keys: VecDeque::new(),
+            pending_ledger: Vec::new(),
+            holds: Vec::new(),
+            last_hold: client.last_hold,
+            slots,
+            version,
+            dirty: false,
+        };
+        let transacoes = client.transacoes;
+        for transacao in transacoes.iter().take(client.ntransacoes as usize) {
+            out.transacoes.push_back(transacao.try_into()?);
+        }
+        let keys = client.keys;
+        out.keys.extend(keys.iter().take(client.nkeys as usize));
+        let holds = client.holds;
+        out.holds.extend(holds.iter().take(client.nholds as usize));
+        Ok(out)
+    }
+    // `lsn` is the WAL record that created the client, 0 when it was not logged.
+    pub fn new(
+        pager: &Pager,
+        id: u32,
+        limite: i64,
+        saldo: i64,
+        lsn: u64,
+    ) -> std::io::Result<Client> {
+        let slots = [pager.allocate()?, pager.allocate()?];
+        let ledger = pager.allocate()?;
+        let mut client = Client {
             id,
             limite,
+            limite_inicial: limite,
             saldo,
+            saldo_inicial: saldo,
             transacoes: VecDeque::new(),
+            lsn,
+            ledger: Ledger::new(ledger),
+            keys: VecDeque::new(),
+            pending_ledger: Vec::new(),
+            holds: Vec::new(),
+            last_hold: 0,
+            slots,
+            version: 0,
+            dirty: false,
         };
-        println!("DB.Client: Creating new DB for id {id} in /client_{id}.db");
-        client.save_client();
-        client
+        println!("DB.Client: Creating new client {id} in pages {slots:?} and {ledger}");
+        client.save_client(pager)?;
+        pager.sync()?;
+        pager.add_entry(id, slots, ledger)?;
+        pager.sync()?;
+        Ok(client)
     }
-    pub fn load_client(id: u8) -> Option<Client> {
-        let buff = std::fs::read(format!("client_{}.db", id));
-        if let Err(e) = buff {
-            eprintln!("DB.Client: Could not load DB from /client_{id}.db: {}", e);
-            return None;
+    pub fn read_slot(pager: &Pager, page: u32) -> Result<(u64, ClientRaw), LoadError> {
+        let buff = pager.read_page(page).map_err(LoadError::Io)?;
+        let header: SlotHeader = unsafe { std::ptr::read(buff.as_ptr() as *const _) };
+        let crc = crc32(&buff[size_of::<u32>()..SIZE_SLOT_HEADER + SIZE_CLIENT_RAW]);
+        if header.crc != crc {
+            return Err(LoadError::BadChecksum {
+                expected: header.crc,
+                found: crc,
+            });
+        }
+        let client: ClientRaw =
+            unsafe { std::ptr::read(buff[SIZE_SLOT_HEADER..].as_ptr() as *const _) };
+        Ok((header.version, client))
+    }
+    // Picks the newest intact slot, a torn write can only damage the one being replaced.
+    pub fn load_client(pager: &Pager, entry: &DirectoryEntry) -> Result<Client, LoadError> {
+        let id = entry.id;
+        let mut newest: Option<(u64, ClientRaw)> = None;
+        let mut error = None;
+        for page in entry.slots {
+            match Client::read_slot(pager, page) {
+                Ok((version, client)) => {
+                    if newest.is_none() || newest.unwrap().0 < version {
+                        newest = Some((version, client));
+                    }
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+        if newest.is_none() {
+            return Err(error.unwrap());
+        }
+        let (version, client) = newest.unwrap();
+        if client.id != id {
+            return Err(LoadError::Invalid("id does not match the directory"));
+        }
+        let ledger = Ledger::open(pager, entry.ledger)?;
+        let client = Client::from_raw(&client, ledger, entry.slots, version)?;
+        println!(
+            "DB.Client: Client {id} loaded ({} transactions in the ledger)",
+            client.ledger.len()
+        );
+        Ok(client)
+    }
+    // Overwrites the older of the two slots, the caller is responsible for syncing the pager.
+    fn save_client(&mut self, pager: &Pager) -> std::io::Result<()> {
+        let version = self.version + 1;
+        let client_raw: ClientRaw = (&*self).into();
+        Client::write_slot(
+            pager,
+            self.slots[(version % 2) as usize],
+            version,
+            &client_raw,
+        )?;
+        self.version = version;
+        Ok(())
+    }
+    pub fn write_slot(
+        pager: &Pager,
+        page: u32,
+        version: u64,
+        client_raw: &ClientRaw,
+    ) -> std::io::Result<()> {
+        let mut buff = [0; SIZE_SLOT_HEADER + SIZE_CLIENT_RAW];
+        let header = SlotHeader { crc: 0, version };
+        let header: &[u8; SIZE_SLOT_HEADER] = unsafe { std::mem::transmute(&header) };
+        let payload: &[u8; SIZE_CLIENT_RAW] = unsafe { std::mem::transmute(client_raw) };
+        buff[..SIZE_SLOT_HEADER].copy_from_slice(header);
+        buff[SIZE_SLOT_HEADER..].copy_from_slice(payload);
+        let crc = crc32(&buff[size_of::<u32>()..]);
+        buff[..size_of::<u32>()].copy_from_slice(&crc.to_ne_bytes());
+        pager.write_at(page, 0, &buff)
+    }
+    pub fn checkpoint(&mut self, pager: &Pager) -> std::io::Result<()> {
+        if !self.dirty {
+            return Ok(());
         }
-        let buff = buff.unwrap();
-        if buff.len() != size_of::<ClientRaw>() {
+        self.save_client(pager)?;
+        self.dirty = false;
+        Ok(())
+    }
+    // The reply to the earlier request with `idempotency_key`, if there was one. Only the
+    // same request gets it, the key on any other is refused.
+    fn replay(
+        &self,
+        idempotency_key: BufferIdempotencyKey,
+        para: u32,
+        transacao: &Transacao,
+    ) -> Option<Result<TransacaoReturn, TransacaoError>> {
+        if idempotency_key == NO_IDEMPOTENCY_KEY {
             return None;
         }
-        let client: &ClientRaw = unsafe { &*(buff.as_ptr() as *const ClientRaw) };
-        println!("DB.Client: Data loaded from /client_{id}.db");
-        Some(client.into())
+        let entry = self
+            .keys
+            .iter()
+            .find(|entry| entry.key == idempotency_key)?;
+        if entry.request != fingerprint(para, transacao) {
+            return Some(Err(TransacaoError::KeyReused));
+        }
+        Some(Ok(entry.ret))
+    }
+    // The record is only buffered in the WAL, `commit_ledger` must be called once it is flushed.
+    // A known idempotency key gets the reply of the first transaction instead.
+    pub fn push_transacao(
+        &mut self,
+        wal: &mut Wal,
+        transacao: Transacao,
+        idempotency_key: BufferIdempotencyKey,
+    ) -> Result<TransacaoReturn, TransacaoError> {
+        if let Some(ret) = self.replay(idempotency_key, 0, &transacao) {
+            return ret;
+        }
+        if self.disponivel() + transacao.value < -self.limite {
+            return Err(TransacaoError::Limit(self.motivo(transacao.value)));
+        }
+        let lsn = wal.write(&Record::transacao(self.id, transacao, idempotency_key));
+        self.update(lsn, transacao, idempotency_key, 0);
+        self.pending_ledger.push((lsn, transacao, Link::default()));
+        Ok(self.ret())
+    }
+    // Moves `transacao.value` from `self` to `para` with a single WAL record, buffered like
+    // `push_transacao`. The reply and the idempotency key are the ones of the payer.
+    pub fn push_transferencia(
+        &mut self,
+        para: &mut Client,
+        wal: &mut Wal,
+        transacao: Transacao,
+        idempotency_key: BufferIdempotencyKey,
+    ) -> Result<TransacaoReturn, TransacaoError> {
+        if transacao.value <= 0 {
+            return Err(TransacaoError::NotPositive);
+        }
+        let debit = Transacao {
+            value: -transacao.value,
+            ..transacao
+        };
+        if let Some(ret) = self.replay(idempotency_key, para.id, &debit) {
+            return ret;
+        }
+        if self.disponivel() + debit.value < -self.limite {
+            return Err(TransacaoError::Limit(self.motivo(debit.value)));
+        }
+        let lsn = wal.write(&Record::transferencia(
+            self.id,
+            debit,
+            para.id,
+            idempotency_key,
+        ));
+        self.push_transferencia_side(lsn, debit, para.id, idempotency_key);
+        para.push_transferencia_side(lsn, transacao, self.id, NO_IDEMPOTENCY_KEY);
+        Ok(self.ret())
     }
-    fn save_client(&self) {
-        let client_raw: ClientRaw = self.into();
-        let buff: &[u8; size_of::<ClientRaw>()] = unsafe { std::mem::transmute(&client_raw) };
-        let _ = std::fs::write(format!("client_{}.db", self.id), buff);
+    fn push_transferencia_side(
+        &mut self,
+        lsn: u64,
+        transacao: Transacao,
+        other: u32,
+        idempotency_key: BufferIdempotencyKey,
+    ) {
+        self.update(lsn, transacao, idempotency_key, other);
+        let link = Link {
+            transferencia: other,
+            ..Link::default()
+        };
+        self.pending_ledger.push((lsn, transacao, link));
     }
-    pub fn push_transacao(&mut self, transacao: Transacao) -> Result<(), ()> {
-        if self.saldo + transacao.value < -self.limite {
+    // Pushes the opposite of the transaction `seq` of the ledger, linked to it so it is
+    // never reversed twice. Buffered in the WAL like `push_transacao`.
+    pub fn push_estorno(
+        &mut self,
+        pager: &Pager,
+        wal: &mut Wal,
+        seq: u64,
+    ) -> Result<TransacaoReturn, EstornoError> {
+        if seq == 0 || seq > self.ledger.len() {
+            return Err(EstornoError::NotFound);
+        }
+        let pending = self
+            .pending_ledger
+            .iter()
+            .any(|(_, _, link)| { link.estorno } == seq);
+        if pending || self.ledger.is_reversed(seq) {
+            return Err(EstornoError::AlreadyReversed);
+        }
+        let (original, link) = self.ledger.read(pager, seq).map_err(EstornoError::Io)?;
+        if link.estorno != 0 {
+            return Err(EstornoError::IsEstorno);
+        }
+        if link.transferencia != 0 {
+            return Err(EstornoError::IsTransferencia);
+        }
+        if link.update_limit {
+            return Err(EstornoError::IsUpdateLimit);
+        }
+        let transacao = described(-original.value, ESTORNO_DESCRIPTION);
+        if self.disponivel() + transacao.value < -self.limite {
+            return Err(EstornoError::Limit(self.motivo(transacao.value), transacao));
+        }
+        let lsn = wal.write(&Record::estorno(self.id, transacao, seq));
+        self.update(lsn, transacao, NO_IDEMPOTENCY_KEY, 0);
+        let link = Link {
+            estorno: seq,
+            ..Link::default()
+        };
+        self.pending_ledger.push((lsn, transacao, link));
+        Ok(self.ret())
+    }
+    // Sets a new limite, refused when `disponivel` is already under it. The change goes to
+    // the ledger as a record that moves no money, buffered in the WAL like `push_transacao`.
+    pub fn push_limite(&mut self, wal: &mut Wal, limite: i64) -> Result<TransacaoReturn, ()> {
+        if limite < 0 || self.disponivel() < -limite {
             return Err(());
         }
+        let transacao = described(0, LIMITE_DESCRIPTION);
+        let lsn = wal.write(&Record::update_limit(self.id, limite, transacao));
+        self.set_limite(lsn, limite);
+        let link = Link {
+            update_limit: true,
+            limite,
+            ..Link::default()
+        };
+        self.pending_ledger.push((lsn, transacao, link));
+        Ok(self.ret())
+    }
+    // Reserves `valor` without touching the saldo. The hold moves no money, so it
+    // never reaches the ledger, only the WAL.
+    pub fn push_hold(&mut self, wal: &mut Wal, valor: i64) -> Result<HoldReturn, HoldError> {
+        if self.holds.len() >= MAX_HOLDS {
+            return Err(HoldError::Full);
+        }
+        if valor <= 0 {
+            return Err(HoldError::NotPositive);
+        }
+        if self.disponivel() - valor < -self.limite {
+            let transacao = described(-valor, RESERVA_DESCRIPTION);
+            return Err(HoldError::Limit(self.motivo(transacao.value), transacao));
+        }
+        let reserva = self.last_hold + 1;
+        let lsn = wal.write(&Record::hold(self.id, reserva, valor));
+        self.apply_hold(lsn, reserva, valor);
+        let ret = self.ret();
+        Ok(HoldReturn {
+            reserva,
+            limite: ret.limite,
+            saldo: ret.saldo,
+            disponivel: ret.disponivel,
+        })
+    }
+    // Debits `transacao.value`, the final amount, in place of the hold. Buffered in the WAL
+    // like `push_transacao`. The amount can be over the hold while the limite allows it.
+    pub fn push_capture(
+        &mut self,
+        wal: &mut Wal,
+        reserva: u64,
+        transacao: Transacao,
+    ) -> Result<TransacaoReturn, HoldError> {
+        let hold = self.holds.iter().find(|hold| hold.reserva == reserva);
+        if hold.is_none() {
+            return Err(HoldError::NotFound);
+        }
+        let valor = hold.unwrap().valor;
+        let debit = Transacao {
+            value: -transacao.value,
+            ..transacao
+        };
+        if transacao.value <= 0 {
+            return Err(HoldError::NotPositive);
+        }
+        // The hold itself is released by the capture, only the others can be in the way.
+        if self.disponivel() + valor + debit.value < -self.limite {
+            return Err(HoldError::Limit(self.motivo(debit.value), debit));
+        }
+        let lsn = wal.write(&Record::capture(self.id, reserva, debit));
+        self.holds.retain(|hold| hold.reserva != reserva);
+        self.update(lsn, debit, NO_IDEMPOTENCY_KEY, 0);
+        self.pending_ledger.push((lsn, debit, Link::default()));
+        Ok(self.ret())
+    }
+    pub fn push_release(
+        &mut self,
+        wal: &mut Wal,
+        reserva: u64,
+    ) -> Result<TransacaoReturn, HoldError> {
+        if !self.holds.iter().any(|hold| hold.reserva == reserva) {
+            return Err(HoldError::NotFound);
+        }
+        let lsn = wal.write(&Record::release(self.id, reserva));
+        self.apply_release(lsn, reserva);
+        Ok(self.ret())
+    }
+    pub fn apply_hold(&mut self, lsn: u64, reserva: u64, valor: i64) {
+        self.holds.push(Hold { reserva, valor });
+        self.last_hold = self.last_hold.max(reserva);
+        self.lsn = lsn;
+        self.dirty = true;
+    }
+    pub fn apply_capture(
+        &mut self,
+        pager: &Pager,
+        lsn: u64,
+        reserva: u64,
+        transacao: Transacao,
+    ) -> std::io::Result<()> {
+        self.holds.retain(|hold| hold.reserva != reserva);
+        self.apply_transacao(pager, lsn, transacao, NO_IDEMPOTENCY_KEY, Link::default())
+    }
+    // Open holds as (reserva, valor), oldest first.
+    pub fn holds(&self) -> Vec<(u64, i64)> {
+        self.holds
+            .iter()
+            .map(|hold| ({ hold.reserva }, { hold.valor }))
+            .collect()
+    }
+    pub fn last_hold(&self) -> u64 {
+        self.last_hold
+    }
+    // Reopens the holds of a dump. `last_hold` also counts the closed ones, so their
+    // numbers are not given out again.
+    pub fn restore_holds(&mut self, lsn: u64, last_hold: u64, holds: &[(u64, i64)]) {
+        for &(reserva, valor) in holds {
+            self.apply_hold(lsn, reserva, valor);
+        }
+        self.last_hold = self.last_hold.max(last_hold);
+        self.lsn = lsn;
+        self.dirty = true;
+    }
+    pub fn apply_release(&mut self, lsn: u64, reserva: u64) {
+        self.holds.retain(|hold| hold.reserva != reserva);
+        self.lsn = lsn;
+        self.dirty = true;
+    }
+    // The saldo minus the open holds, the limite applies to it.
+    pub fn disponivel(&self) -> i64 {
+        self.saldo - self.holds.iter().map(|hold| hold.valor).sum::<i64>()
+    }
+    // Why `value` does not fit, once it is known it does not.
+    fn motivo(&self, value: i64) -> Motivo {
+        if self.saldo + value < -self.limite {
+            Motivo::Limite
+        } else {
+            Motivo::Reservas
+        }
+    }
+    fn ret(&self) -> TransacaoReturn {
+        TransacaoReturn {
+            limite: self.limite,
+            saldo: self.saldo,
+            disponivel: self.disponivel(),
+        }
+    }
+    // Applies a ledger record already accepted and logged, so there is no limit check.
+    pub fn apply_transacao(
+        &mut self,
+        pager: &Pager,
+        lsn: u64,
+        transacao: Transacao,
+        idempotency_key: BufferIdempotencyKey,
+        link: Link,
+    ) -> std::io::Result<()> {
+        self.ledger.append(pager, lsn, &transacao, link)?;
+        if link.update_limit {
+            self.set_limite(lsn, link.limite);
+        } else {
+            self.update(lsn, transacao, idempotency_key, link.transferencia);
+        }
+        Ok(())
+    }
+    fn set_limite(&mut self, lsn: u64, limite: i64) {
+        self.limite = limite;
+        self.lsn = lsn;
+        self.dirty = true;
+    }
+    // The ledger only gets records already durable in the WAL, otherwise a crash could
+    // leave in it a transaction the WAL and the client never had.
+    pub fn commit_ledger(&mut self, pager: &Pager) -> std::io::Result<()> {
+        for (lsn, transacao, link) in self.pending_ledger.iter() {
+            self.ledger.append(pager, *lsn, transacao, *link)?;
+        }
+        self.pending_ledger.clear();
+        Ok(())
+    }
+    // `para` is the payee when this is the payer of a transfer, for the idempotency key.
+    fn update(
+        &mut self,
+        lsn: u64,
+        transacao: Transacao,
+        idempotency_key: BufferIdempotencyKey,
+        para: u32,
+    ) {
         self.saldo += transacao.value;
         if self.transacoes.len() >= NTRANSACOES {
             self.transacoes.pop_back();
         }
         self.transacoes.push_front(transacao);
-        self.save_client();
+        if idempotency_key != NO_IDEMPOTENCY_KEY {
+            if self.keys.len() >= IDEMPOTENCY_KEYS {
+                self.keys.pop_front();
+            }
+            self.keys.push_back(IdempotencyEntry {
+                key: idempotency_key,
+                request: fingerprint(para, &transacao),
+                ret: self.ret(),
+            });
+        }
+        self.lsn = lsn;
+        self.dirty = true;
+    }
+    // Replaces the state with a snapshot sent by the primary. The ledger is not part of
+    // the snapshot, so the one of a replica misses the transactions it skipped.
+    pub fn restore(&mut self, client: &ClientRaw) -> Result<(), LoadError> {
+        if client.ntransacoes > NTRANSACOES as u32 {
+            return Err(LoadError::Invalid("more transactions than NTRANSACOES"));
+        }
+        if client.nkeys > IDEMPOTENCY_KEYS as u32 {
+            return Err(LoadError::Invalid(
+                "more idempotency keys than IDEMPOTENCY_KEYS",
+            ));
+        }
+        if client.nholds > MAX_HOLDS as u32 {
+            return Err(LoadError::Invalid("more holds than MAX_HOLDS"));
+        }
+        let mut transacoes = VecDeque::new();
+        let raw_transacoes = client.transacoes;
+        for transacao in raw_transacoes.iter().take(client.ntransacoes as usize) {
+            transacoes.push_back(transacao.try_into()?);
+        }
+        self.limite = client.limite;
+        self.limite_inicial = client.limite_inicial;
+        self.saldo = client.saldo;
+        self.saldo_inicial = client.saldo_inicial;
+        self.transacoes = transacoes;
+        let keys = client.keys;
+        self.keys = keys.iter().take(client.nkeys as usize).copied().collect();
+        let holds = client.holds;
+        self.holds = holds.iter().take(client.nholds as usize).copied().collect();
+        self.last_hold = client.last_hold;
+        self.lsn = client.lsn;
+        self.dirty = true;
         Ok(())
     }
     pub fn extrato(&self) -> Extrato {
@@ -122,7 +954,448 @@ impl Client {
         Extrato {
             total: self.saldo,
             limite: self.limite,
+            disponivel: self.disponivel(),
             transacoes,
+            mais: false,
+        }
+    }
+    // Like `extrato`, but with the newest transactions of the whole ledger that pass
+    // `filtro`. Changes of the limite move no money and are left out as well. The
+    // transactions still in the WAL buffer are newer than the ledger, they come first.
+    pub fn extrato_filtrado(&self, pager: &Pager, filtro: Filtro) -> std::io::Result<Extrato> {
+        let mut extrato = self.extrato();
+        for transacao in extrato.transacoes.iter_mut() {
+            transacao.isvalid = false;
+        }
+        let mut found = 0;
+        // Returns false once the extrato is full and another transaction passes.
+        let mut add = |transacao: &Transacao, link: &Link| {
+            if link.update_limit || !passes(&filtro, transacao) {
+                return true;
+            }
+            if found == NTRANSACOES {
+                extrato.mais = true;
+                return false;
+            }
+            extrato.transacoes[found] = TransacaoExtrato {
+                isvalid: true,
+                value: transacao.value,
+                transacao_description: transacao.transacao_description,
+                timestap: transacao.timestap,
+            };
+            found += 1;
+            true
+        };
+        let pending_done = self
+            .pending_ledger
+            .iter()
+            .rev()
+            .all(|(_, transacao, link)| add(transacao, link));
+        if pending_done {
+            self.ledger
+                .scan_rev(pager, |_, transacao, link| add(&transacao, &link))?;
+        }
+        Ok(extrato)
+    }
+    // Undoes every transaction made after `em`, in nanoseconds since the epoch. The
+    // timestamps come from the backends, so the ledger is not sorted by them and is read
+    // whole, newest first. The transactions still in the WAL buffer are already in the saldo.
+    pub fn saldo_em(&self, pager: &Pager, em: u64) -> std::io::Result<SaldoReturn> {
+        let mut saldo = self.saldo;
+        // Timestamp and value of the newest change of the limite made up to `em`. On a tie
+        // the later one in the ledger wins, and it is seen first.
+        let mut limite: Option<(u64, i64)> = None;
+        let mut undo = |transacao: Transacao, link: Link| {
+            let timestamp = nanos(&transacao);
+            if timestamp > em {
+                saldo -= transacao.value;
+            } else if link.update_limit && limite.is_none_or(|(last, _)| last < timestamp) {
+                limite = Some((timestamp, link.limite));
+            }
+        };
+        for (_, transacao, link) in self.pending_ledger.iter().rev() {
+            undo(*transacao, *link);
         }
+        self.ledger.scan_rev(pager, |_, transacao, link| {
+            undo(transacao, link);
+            true
+        })?;
+        Ok(SaldoReturn {
+            saldo,
+            limite: limite.map_or(self.limite_inicial, |(_, limite)| limite),
+        })
+    }
+    pub fn transacoes(
+        &self,
+        pager: &Pager,
+        pagina: Pagina,
+    ) -> std::io::Result<(PaginaHeader, Vec<TransacaoLedger>)> {
+        let limit = match pagina.limit as usize {
+            0 => NTRANSACOES,
+            limit => limit.min(MAX_PAGINA),
+        };
+        let page = self.ledger.read_before(pager, pagina.before, limit)?;
+        let next_before = match page.last() {
+            Some((seq, _, _)) if *seq > 1 => *seq,
+            _ => 0,
+        };
+        let transacoes: Vec<TransacaoLedger> = page
+            .iter()
+            .map(|(seq, transacao, link)| TransacaoLedger {
+                seq: *seq,
+                value: transacao.value,
+                transacao_description: transacao.transacao_description,
+                timestap: transacao.timestap,
+                estorno: link.estorno,
+                transferencia: link.transferencia,
+                update_limit: link.update_limit,
+                limite: link.limite,
+            })
+            .collect();
+        let header = PaginaHeader {
+            ntransacoes: transacoes.len() as u32,
+            next_before,
+        };
+        Ok((header, transacoes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageKind;
+    use std::path::Path;
+
+    fn open() -> (Pager, Wal) {
+        let pager = Pager::open(Path::new("client-test"), StorageKind::Memory).unwrap();
+        (pager, Wal::memory(0))
+    }
+
+    fn transacao(value: i64, description: &str, secs: u64) -> Transacao {
+        Transacao {
+            timestap: UNIX_EPOCH + Duration::from_secs(secs),
+            ..described(value, description)
+        }
+    }
+
+    fn key(name: &str) -> BufferIdempotencyKey {
+        let mut key = NO_IDEMPOTENCY_KEY;
+        key[..name.len()].copy_from_slice(name.as_bytes());
+        key
+    }
+
+    fn secs(timestamp: SystemTime) -> u64 {
+        timestamp.duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn idempotency_keys() {
+        let (pager, mut wal) = open();
+        let mut client = Client::new(&pager, 1, 1000, 0, 0).unwrap();
+        let mut other = Client::new(&pager, 2, 1000, 0, 0).unwrap();
+        let first = client
+            .push_transacao(&mut wal, transacao(100, "a", 1), key("k"))
+            .unwrap();
+        // A retry comes with a new timestamp, it gets the first reply and applies nothing.
+        let retry = client
+            .push_transacao(&mut wal, transacao(100, "a", 2), key("k"))
+            .unwrap();
+        assert_eq!({ retry.saldo }, { first.saldo });
+        assert_eq!((client.saldo, wal.last_lsn()), (100, 1));
+
+        // The key on another valor, tipo, descricao or on a transfer is refused.
+        for reused in [
+            transacao(101, "a", 1),
+            transacao(-100, "a", 1),
+            transacao(100, "b", 1),
+        ] {
+            let ret = client.push_transacao(&mut wal, reused, key("k"));
+            assert!(matches!(ret, Err(TransacaoError::KeyReused)));
+        }
+        let ret = client.push_transferencia(&mut other, &mut wal, transacao(100, "a", 1), key("k"));
+        assert!(matches!(ret, Err(TransacaoError::KeyReused)));
+        assert_eq!((client.saldo, other.saldo, wal.last_lsn()), (100, 0, 1));
+
+        // Only the last IDEMPOTENCY_KEYS are remembered.
+        for indx in 0..IDEMPOTENCY_KEYS {
+            client
+                .push_transacao(&mut wal, transacao(1, "x", 1), key(&format!("k{indx}")))
+                .unwrap();
+        }
+        client
+            .push_transacao(&mut wal, transacao(100, "a", 3), key("k"))
+            .unwrap();
+        assert_eq!(client.saldo, 200 + IDEMPOTENCY_KEYS as i64);
+    }
+
+    #[test]
+    fn estorno() {
+        let (pager, mut wal) = open();
+        let mut client = Client::new(&pager, 1, 100, 0, 0).unwrap();
+        let mut other = Client::new(&pager, 2, 100, 0, 0).unwrap();
+        client
+            .push_transacao(&mut wal, transacao(500, "salario", 1), NO_IDEMPOTENCY_KEY)
+            .unwrap();
+        client
+            .push_transacao(&mut wal, transacao(-550, "aluguel", 2), NO_IDEMPOTENCY_KEY)
+            .unwrap();
+        client
+            .push_transferencia(
+                &mut other,
+                &mut wal,
+                transacao(10, "pix", 3),
+                NO_IDEMPOTENCY_KEY,
+            )
+            .unwrap();
+        client.commit_ledger(&pager).unwrap();
+
+        // Undoing the credit would take the saldo from -60 to -560.
+        let ret = client.push_estorno(&pager, &mut wal, 1);
+        assert!(matches!(ret, Err(EstornoError::Limit(Motivo::Limite, _))));
+        let ret = client.push_estorno(&pager, &mut wal, 2).unwrap();
+        assert_eq!({ ret.saldo }, 490);
+
+        // Refused twice, whether the first reversal is still buffered or in the ledger.
+        let ret = client.push_estorno(&pager, &mut wal, 2);
+        assert!(matches!(ret, Err(EstornoError::AlreadyReversed)));
+        client.commit_ledger(&pager).unwrap();
+        let ret = client.push_estorno(&pager, &mut wal, 2);
+        assert!(matches!(ret, Err(EstornoError::AlreadyReversed)));
+
+        let ret = client.push_estorno(&pager, &mut wal, 4);
+        assert!(matches!(ret, Err(EstornoError::IsEstorno)));
+        let ret = client.push_estorno(&pager, &mut wal, 3);
+        assert!(matches!(ret, Err(EstornoError::IsTransferencia)));
+        for seq in [0, 5] {
+            let ret = client.push_estorno(&pager, &mut wal, seq);
+            assert!(matches!(ret, Err(EstornoError::NotFound)));
+        }
+
+        let (reversal, link) = client.ledger.read(&pager, 4).unwrap();
+        assert_eq!(({ reversal.value }, { link.estorno }), (550, 2));
+        assert_eq!(client.saldo, 490);
+    }
+
+    #[test]
+    fn transferencia_moves_both_sides_or_none() {
+        let (pager, mut wal) = open();
+        let mut payer = Client::new(&pager, 1, 100, 50, 0).unwrap();
+        let mut payee = Client::new(&pager, 2, 0, 0, 0).unwrap();
+        let ret = payer
+            .push_transferencia(&mut payee, &mut wal, transacao(120, "aluguel", 1), key("t"))
+            .unwrap();
+        assert_eq!(({ ret.saldo }, payer.saldo, payee.saldo), (-70, -70, 120));
+        // Both sides go in a single record.
+        assert_eq!(wal.nrecords(), 1);
+
+        let ret = payer.push_transferencia(
+            &mut payee,
+            &mut wal,
+            transacao(31, "x", 2),
+            NO_IDEMPOTENCY_KEY,
+        );
+        assert!(matches!(ret, Err(TransacaoError::Limit(Motivo::Limite))));
+        for value in [0, -5] {
+            let ret = payer.push_transferencia(
+                &mut payee,
+                &mut wal,
+                transacao(value, "x", 2),
+                NO_IDEMPOTENCY_KEY,
+            );
+            assert!(matches!(ret, Err(TransacaoError::NotPositive)));
+        }
+        assert_eq!((payer.saldo, payee.saldo, wal.nrecords()), (-70, 120, 1));
+
+        payer.commit_ledger(&pager).unwrap();
+        payee.commit_ledger(&pager).unwrap();
+        let (debit, link) = payer.ledger.read(&pager, 1).unwrap();
+        assert_eq!(({ debit.value }, { link.transferencia }), (-120, 2));
+        let (credit, link) = payee.ledger.read(&pager, 1).unwrap();
+        assert_eq!(({ credit.value }, { link.transferencia }), (120, 1));
+    }
+
+    #[test]
+    fn holds() {
+        let (pager, mut wal) = open();
+        let mut client = Client::new(&pager, 1, 100, 0, 0).unwrap();
+        let hold = client.push_hold(&mut wal, 60).unwrap();
+        assert_eq!(
+            ({ hold.reserva }, { hold.saldo }, { hold.disponivel }),
+            (1, 0, -60)
+        );
+
+        // The saldo has room for these, not once the hold is taken out.
+        let ret = client.push_transacao(&mut wal, transacao(-50, "x", 1), NO_IDEMPOTENCY_KEY);
+        assert!(matches!(ret, Err(TransacaoError::Limit(Motivo::Reservas))));
+        let ret = client.push_hold(&mut wal, 50);
+        assert!(matches!(ret, Err(HoldError::Limit(Motivo::Reservas, _))));
+        let ret = client.push_hold(&mut wal, 0);
+        assert!(matches!(ret, Err(HoldError::NotPositive)));
+
+        // The capture takes the place of the hold, for more than it while the limite allows.
+        let ret = client
+            .push_capture(&mut wal, 1, transacao(90, "loja", 2))
+            .unwrap();
+        assert_eq!(({ ret.saldo }, { ret.disponivel }), (-90, -90));
+        let ret = client.push_capture(&mut wal, 1, transacao(1, "loja", 3));
+        assert!(matches!(ret, Err(HoldError::NotFound)));
+
+        let hold = client.push_hold(&mut wal, 10).unwrap();
+        assert_eq!(({ hold.reserva }, client.disponivel()), (2, -100));
+        client.push_release(&mut wal, 2).unwrap();
+        assert_eq!((client.saldo, client.disponivel()), (-90, -90));
+        assert!(matches!(
+            client.push_release(&mut wal, 2),
+            Err(HoldError::NotFound)
+        ));
+
+        let mut client = Client::new(&pager, 2, 1000, 0, 0).unwrap();
+        for _ in 0..MAX_HOLDS {
+            client.push_hold(&mut wal, 1).unwrap();
+        }
+        assert!(matches!(
+            client.push_hold(&mut wal, 1),
+            Err(HoldError::Full)
+        ));
+    }
+
+    #[test]
+    fn extrato_filtrado() {
+        let (pager, mut wal) = open();
+        let mut client = Client::new(&pager, 1, 1000, 0, 0).unwrap();
+        // Credits on the even seconds, debits on the odd ones. The last ten are still in
+        // the WAL buffer.
+        for secs in 1..=30 {
+            let value = if secs % 2 == 0 { 10 } else { -1 };
+            client
+                .push_transacao(&mut wal, transacao(value, "x", secs), NO_IDEMPOTENCY_KEY)
+                .unwrap();
+            if secs == 20 {
+                client.commit_ledger(&pager).unwrap();
+            }
+        }
+        let filtered = |tipo, de: u64, ate: u64| {
+            let filtro = Filtro {
+                ativo: true,
+                tipo,
+                de: de * 1_000_000_000,
+                ate: ate * 1_000_000_000,
+            };
+            let extrato = client.extrato_filtrado(&pager, filtro).unwrap();
+            let transacoes = extrato.transacoes;
+            let found: Vec<u64> = transacoes
+                .iter()
+                .filter(|transacao| transacao.isvalid)
+                .map(|transacao| secs(transacao.timestap))
+                .collect();
+            (found, extrato.mais)
+        };
+
+        assert_eq!(filtered(b'c', 15, 24), (vec![24, 22, 20, 18, 16], false));
+        assert_eq!(filtered(0, 19, 21), (vec![21, 20, 19], false));
+        // More debits pass than fit in the extrato.
+        let (found, mais) = filtered(b'd', 0, 30);
+        assert_eq!(found, (11..=29).rev().step_by(2).collect::<Vec<u64>>());
+        assert!(mais);
+    }
+
+    #[test]
+    fn saldo_em() {
+        let (pager, mut wal) = open();
+        let mut client = Client::new(&pager, 1, 100, 50, 0).unwrap();
+        // A transaction at the epoch is not mistaken for the saldo the client started with.
+        client
+            .push_transacao(&mut wal, transacao(10, "a", 0), NO_IDEMPOTENCY_KEY)
+            .unwrap();
+        client
+            .push_transacao(&mut wal, transacao(-20, "b", 20), NO_IDEMPOTENCY_KEY)
+            .unwrap();
+        client.commit_ledger(&pager).unwrap();
+        // Two changes of the limite at the same time, the later one wins.
+        for limite in [300, 500] {
+            let change = transacao(0, LIMITE_DESCRIPTION, 20);
+            let lsn = wal.write(&Record::update_limit(1, limite, change));
+            let link = Link {
+                update_limit: true,
+                limite,
+                ..Link::default()
+            };
+            client
+                .apply_transacao(&pager, lsn, change, NO_IDEMPOTENCY_KEY, link)
+                .unwrap();
+        }
+        // Older than the last one in the ledger, and still in the WAL buffer.
+        client
+            .push_transacao(&mut wal, transacao(5, "c", 10), NO_IDEMPOTENCY_KEY)
+            .unwrap();
+
+        let at = |secs: u64| {
+            let ret = client.saldo_em(&pager, secs * 1_000_000_000).unwrap();
+            ({ ret.saldo }, { ret.limite })
+        };
+        assert_eq!(at(0), (60, 100));
+        assert_eq!(at(10), (65, 100));
+        assert_eq!(at(20), (45, 500));
+        assert_eq!((client.saldo, client.saldo_inicial), (45, 50));
+    }
+
+    #[test]
+    fn transacoes_pages_across_ledger_pages() {
+        let (pager, mut wal) = open();
+        let mut client = Client::new(&pager, 1, 0, 0, 0).unwrap();
+        for secs in 1..=150 {
+            client
+                .push_transacao(&mut wal, transacao(1, "x", secs), NO_IDEMPOTENCY_KEY)
+                .unwrap();
+        }
+        client.commit_ledger(&pager).unwrap();
+
+        let mut seqs = Vec::new();
+        let mut pages = 0;
+        let mut before = 0;
+        loop {
+            let pagina = Pagina { limit: 40, before };
+            let (header, transacoes) = client.transacoes(&pager, pagina).unwrap();
+            assert_eq!({ header.ntransacoes } as usize, transacoes.len());
+            seqs.extend(transacoes.iter().map(|transacao| transacao.seq));
+            pages += 1;
+            before = header.next_before;
+            if before == 0 {
+                break;
+            }
+        }
+        assert_eq!(seqs, (1..=150).rev().collect::<Vec<u64>>());
+        assert_eq!(pages, 4);
+
+        // No limit is the size of the extrato, the most is MAX_PAGINA.
+        let (header, transacoes) = client
+            .transacoes(
+                &pager,
+                Pagina {
+                    limit: 0,
+                    before: 0,
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            (transacoes.len(), { header.next_before }),
+            (NTRANSACOES, 141)
+        );
+        let (_, transacoes) = client
+            .transacoes(
+                &pager,
+                Pagina {
+                    limit: 1000,
+                    before: 0,
+                },
+            )
+            .unwrap();
+        assert_eq!(transacoes.len(), MAX_PAGINA);
+
+        // The whole chain is found again once the client is loaded back.
+        client.checkpoint(&pager).unwrap();
+        let entry = pager.entries().into_iter().find(|entry| entry.id == 1);
+        let loaded = Client::load_client(&pager, &entry.unwrap()).unwrap();
+        assert_eq!((loaded.ledger.len(), loaded.saldo), (150, 150));
     }
 }
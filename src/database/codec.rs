@@ -0,0 +1,841 @@
+use crate::{
+    BufferDescription, ClienteReturn, Extrato, Filtro, HoldReturn, Motivo, NovoCliente, Operation,
+    OperationKind, Pagina, PaginaHeader, Rejeicao, SaldoReturn, Status, Transacao,
+    TransacaoExtrato, TransacaoLedger, TransacaoReturn, MAX_PAGINA, NCHAR_DESCRIPTION, NTRANSACOES,
+    SIZE_IDEMPOTENCY_KEY,
+};
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, UNIX_EPOCH};
+
+// Every field is written one after the other in little-endian, with no padding. A
+// `SystemTime` is a `u64` of nanoseconds since the epoch, a `char` its `u32` code point
+// and a `bool` one byte, 0 or 1.
+pub trait Encode {
+    // Bytes `encode` appends, every message has a fixed size.
+    const SIZE: usize;
+    fn encode(&self, out: &mut Vec<u8>);
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(Self::SIZE);
+        self.encode(&mut out);
+        debug_assert_eq!(out.len(), Self::SIZE);
+        out
+    }
+}
+
+pub trait Decode: Sized {
+    fn decode(input: &mut Reader) -> Result<Self, DecodeError>;
+    // `buff` must hold exactly one value.
+    fn from_bytes(buff: &[u8]) -> Result<Self, DecodeError> {
+        let mut input = Reader { buff };
+        let value = Self::decode(&mut input)?;
+        if !input.buff.is_empty() {
+            return Err(DecodeError::TrailingBytes(input.buff.len()));
+        }
+        Ok(value)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    Truncated,
+    TrailingBytes(usize),
+    InvalidKind(u32),
+    InvalidStatus(u8),
+    InvalidMotivo(u32),
+    // A page announcing more than `MAX_PAGINA` items.
+    TooManyItems(u32),
+    InvalidBool(u8),
+    InvalidChar(u32),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "message is truncated"),
+            DecodeError::TrailingBytes(n) => write!(f, "{n} bytes after the message"),
+            DecodeError::InvalidKind(kind) => write!(f, "unknown operation kind {kind}"),
+            DecodeError::InvalidStatus(status) => write!(f, "unknown status {status}"),
+            DecodeError::InvalidMotivo(motivo) => write!(f, "unknown motivo {motivo}"),
+            DecodeError::TooManyItems(n) => write!(f, "page of {n} items"),
+            DecodeError::InvalidBool(byte) => write!(f, "invalid bool {byte}"),
+            DecodeError::InvalidChar(ch) => write!(f, "invalid code point {ch:#x}"),
+        }
+    }
+}
+
+pub struct Reader<'a> {
+    buff: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        if self.buff.len() < N {
+            return Err(DecodeError::Truncated);
+        }
+        let (bytes, rest) = self.buff.split_at(N);
+        self.buff = rest;
+        Ok(bytes.try_into().unwrap())
+    }
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take::<1>()?[0])
+    }
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+    fn u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+    fn i64(&mut self) -> Result<i64, DecodeError> {
+        Ok(i64::from_le_bytes(self.take()?))
+    }
+    fn bool(&mut self) -> Result<bool, DecodeError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            byte => Err(DecodeError::InvalidBool(byte)),
+        }
+    }
+    fn description(&mut self) -> Result<BufferDescription, DecodeError> {
+        let mut description = ['\0'; NCHAR_DESCRIPTION];
+        for ch in description.iter_mut() {
+            let code = self.u32()?;
+            *ch = char::from_u32(code).ok_or(DecodeError::InvalidChar(code))?;
+        }
+        Ok(description)
+    }
+    fn timestamp(&mut self) -> Result<std::time::SystemTime, DecodeError> {
+        Ok(UNIX_EPOCH + Duration::from_nanos(self.u64()?))
+    }
+}
+
+fn put_description(out: &mut Vec<u8>, description: &BufferDescription) {
+    for ch in description {
+        out.extend((*ch as u32).to_le_bytes());
+    }
+}
+
+// Times before the epoch are sent as the epoch.
+fn put_timestamp(out: &mut Vec<u8>, timestamp: std::time::SystemTime) {
+    let nanos = timestamp
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    out.extend(nanos.to_le_bytes());
+}
+
+const SIZE_DESCRIPTION: usize = NCHAR_DESCRIPTION * 4;
+
+impl Encode for Transacao {
+    const SIZE: usize = 8 + SIZE_DESCRIPTION + 8;
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend({ self.value }.to_le_bytes());
+        put_description(out, &{ self.transacao_description });
+        put_timestamp(out, self.timestap);
+    }
+}
+
+impl Decode for Transacao {
+    fn decode(input: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Transacao {
+            value: input.i64()?,
+            transacao_description: input.description()?,
+            timestap: input.timestamp()?,
+        })
+    }
+}
+
+impl Encode for Pagina {
+    const SIZE: usize = 4 + 8;
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend({ self.limit }.to_le_bytes());
+        out.extend({ self.before }.to_le_bytes());
+    }
+}
+
+impl Decode for Pagina {
+    fn decode(input: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Pagina {
+            limit: input.u32()?,
+            before: input.u64()?,
+        })
+    }
+}
+
+impl Encode for Filtro {
+    const SIZE: usize = 1 + 1 + 8 + 8;
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(self.ativo as u8);
+        out.push(self.tipo);
+        out.extend({ self.de }.to_le_bytes());
+        out.extend({ self.ate }.to_le_bytes());
+    }
+}
+
+impl Decode for Filtro {
+    fn decode(input: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Filtro {
+            ativo: input.bool()?,
+            tipo: input.u8()?,
+            de: input.u64()?,
+            ate: input.u64()?,
+        })
+    }
+}
+
+impl Encode for NovoCliente {
+    const SIZE: usize = 8 + 8;
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend({ self.limite }.to_le_bytes());
+        out.extend({ self.saldo }.to_le_bytes());
+    }
+}
+
+impl Decode for NovoCliente {
+    fn decode(input: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(NovoCliente {
+            limite: input.i64()?,
+            saldo: input.i64()?,
+        })
+    }
+}
+
+impl OperationKind {
+    fn from_u32(kind: u32) -> Result<OperationKind, DecodeError> {
+        Ok(match kind {
+            0 => OperationKind::Extrato,
+            1 => OperationKind::Transacao,
+            2 => OperationKind::Transacoes,
+            3 => OperationKind::CreateClient,
+            4 => OperationKind::Estorno,
+            5 => OperationKind::Transferencia,
+            6 => OperationKind::UpdateLimit,
+            7 => OperationKind::Hold,
+            8 => OperationKind::Capture,
+            9 => OperationKind::Release,
+            10 => OperationKind::Rejeicoes,
+            11 => OperationKind::Saldo,
+            kind => return Err(DecodeError::InvalidKind(kind)),
+        })
+    }
+}
+
+impl Encode for Operation {
+    const SIZE: usize = 8
+        + 4
+        + 4
+        + Transacao::SIZE
+        + Pagina::SIZE
+        + Filtro::SIZE
+        + 8
+        + NovoCliente::SIZE
+        + 8
+        + 4
+        + 8
+        + 8
+        + SIZE_IDEMPOTENCY_KEY;
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend({ self.request_id }.to_le_bytes());
+        out.extend((self.kind as u32).to_le_bytes());
+        out.extend({ self.id }.to_le_bytes());
+        { self.transacao }.encode(out);
+        { self.pagina }.encode(out);
+        { self.filtro }.encode(out);
+        out.extend({ self.em }.to_le_bytes());
+        { self.novo_cliente }.encode(out);
+        out.extend({ self.estorno }.to_le_bytes());
+        out.extend({ self.para }.to_le_bytes());
+        out.extend({ self.limite }.to_le_bytes());
+        out.extend({ self.reserva }.to_le_bytes());
+        out.extend(self.idempotency_key);
+    }
+}
+
+impl Decode for Operation {
+    fn decode(input: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Operation {
+            request_id: input.u64()?,
+            kind: OperationKind::from_u32(input.u32()?)?,
+            id: input.u32()?,
+            transacao: Transacao::decode(input)?,
+            pagina: Pagina::decode(input)?,
+            filtro: Filtro::decode(input)?,
+            em: input.u64()?,
+            novo_cliente: NovoCliente::decode(input)?,
+            estorno: input.u64()?,
+            para: input.u32()?,
+            limite: input.i64()?,
+            reserva: input.u64()?,
+            idempotency_key: input.take()?,
+        })
+    }
+}
+
+impl Encode for TransacaoExtrato {
+    const SIZE: usize = 1 + 8 + SIZE_DESCRIPTION + 8;
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(self.isvalid as u8);
+        out.extend({ self.value }.to_le_bytes());
+        put_description(out, &{ self.transacao_description });
+        put_timestamp(out, self.timestap);
+    }
+}
+
+impl Decode for TransacaoExtrato {
+    fn decode(input: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(TransacaoExtrato {
+            isvalid: input.bool()?,
+            value: input.i64()?,
+            transacao_description: input.description()?,
+            timestap: input.timestamp()?,
+        })
+    }
+}
+
+impl Encode for Extrato {
+    const SIZE: usize = 8 + 8 + 8 + NTRANSACOES * TransacaoExtrato::SIZE + 1;
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend({ self.total }.to_le_bytes());
+        out.extend({ self.limite }.to_le_bytes());
+        out.extend({ self.disponivel }.to_le_bytes());
+        for transacao in self.transacoes {
+            transacao.encode(out);
+        }
+        out.push(self.mais as u8);
+    }
+}
+
+impl Decode for Extrato {
+    fn decode(input: &mut Reader) -> Result<Self, DecodeError> {
+        let total = input.i64()?;
+        let limite = input.i64()?;
+        let disponivel = input.i64()?;
+        let mut transacoes = [TransacaoExtrato {
+            isvalid: false,
+            value: 0,
+            transacao_description: ['\0'; NCHAR_DESCRIPTION],
+            timestap: UNIX_EPOCH,
+        }; NTRANSACOES];
+        for transacao in transacoes.iter_mut() {
+            *transacao = TransacaoExtrato::decode(input)?;
+        }
+        Ok(Extrato {
+            total,
+            limite,
+            disponivel,
+            transacoes,
+            mais: input.bool()?,
+        })
+    }
+}
+
+impl Encode for TransacaoReturn {
+    const SIZE: usize = 8 + 8 + 8;
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend({ self.limite }.to_le_bytes());
+        out.extend({ self.saldo }.to_le_bytes());
+        out.extend({ self.disponivel }.to_le_bytes());
+    }
+}
+
+impl Decode for TransacaoReturn {
+    fn decode(input: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(TransacaoReturn {
+            limite: input.i64()?,
+            saldo: input.i64()?,
+            disponivel: input.i64()?,
+        })
+    }
+}
+
+impl Encode for HoldReturn {
+    const SIZE: usize = 8 + 8 + 8 + 8;
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend({ self.reserva }.to_le_bytes());
+        out.extend({ self.limite }.to_le_bytes());
+        out.extend({ self.saldo }.to_le_bytes());
+        out.extend({ self.disponivel }.to_le_bytes());
+    }
+}
+
+impl Decode for HoldReturn {
+    fn decode(input: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(HoldReturn {
+            reserva: input.u64()?,
+            limite: input.i64()?,
+            saldo: input.i64()?,
+            disponivel: input.i64()?,
+        })
+    }
+}
+
+impl Encode for SaldoReturn {
+    const SIZE: usize = 8 + 8;
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend({ self.saldo }.to_le_bytes());
+        out.extend({ self.limite }.to_le_bytes());
+    }
+}
+
+impl Decode for SaldoReturn {
+    fn decode(input: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(SaldoReturn {
+            saldo: input.i64()?,
+            limite: input.i64()?,
+        })
+    }
+}
+
+impl Encode for ClienteReturn {
+    const SIZE: usize = 4 + 8 + 8;
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend({ self.id }.to_le_bytes());
+        out.extend({ self.limite }.to_le_bytes());
+        out.extend({ self.saldo }.to_le_bytes());
+    }
+}
+
+impl Decode for ClienteReturn {
+    fn decode(input: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(ClienteReturn {
+            id: input.u32()?,
+            limite: input.i64()?,
+            saldo: input.i64()?,
+        })
+    }
+}
+
+impl Encode for TransacaoLedger {
+    const SIZE: usize = 8 + 8 + SIZE_DESCRIPTION + 8 + 8 + 4 + 1 + 8;
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend({ self.seq }.to_le_bytes());
+        out.extend({ self.value }.to_le_bytes());
+        put_description(out, &{ self.transacao_description });
+        put_timestamp(out, self.timestap);
+        out.extend({ self.estorno }.to_le_bytes());
+        out.extend({ self.transferencia }.to_le_bytes());
+        out.push(self.update_limit as u8);
+        out.extend({ self.limite }.to_le_bytes());
+    }
+}
+
+impl Decode for TransacaoLedger {
+    fn decode(input: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(TransacaoLedger {
+            seq: input.u64()?,
+            value: input.i64()?,
+            transacao_description: input.description()?,
+            timestap: input.timestamp()?,
+            estorno: input.u64()?,
+            transferencia: input.u32()?,
+            update_limit: input.bool()?,
+            limite: input.i64()?,
+        })
+    }
+}
+
+impl Encode for PaginaHeader {
+    const SIZE: usize = 4 + 8;
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend({ self.ntransacoes }.to_le_bytes());
+        out.extend({ self.next_before }.to_le_bytes());
+    }
+}
+
+impl Decode for PaginaHeader {
+    fn decode(input: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(PaginaHeader {
+            ntransacoes: input.u32()?,
+            next_before: input.u64()?,
+        })
+    }
+}
+
+// A `PaginaHeader` followed by its `ntransacoes` items.
+pub fn encode_pagina<T: Encode>(header: &PaginaHeader, items: &[T]) -> Vec<u8> {
+    debug_assert_eq!({ header.ntransacoes } as usize, items.len());
+    let mut out = Vec::with_capacity(PaginaHeader::SIZE + items.len() * T::SIZE);
+    header.encode(&mut out);
+    for item in items {
+        item.encode(&mut out);
+    }
+    out
+}
+
+pub fn decode_pagina<T: Decode>(buff: &[u8]) -> Result<(PaginaHeader, Vec<T>), DecodeError> {
+    let mut input = Reader { buff };
+    let header = PaginaHeader::decode(&mut input)?;
+    if header.ntransacoes as usize > MAX_PAGINA {
+        return Err(DecodeError::TooManyItems(header.ntransacoes));
+    }
+    let mut items = Vec::with_capacity(header.ntransacoes as usize);
+    for _ in 0..header.ntransacoes {
+        items.push(T::decode(&mut input)?);
+    }
+    if !input.buff.is_empty() {
+        return Err(DecodeError::TrailingBytes(input.buff.len()));
+    }
+    Ok((header, items))
+}
+
+impl Motivo {
+    fn from_u32(motivo: u32) -> Result<Motivo, DecodeError> {
+        match motivo {
+            0 => Ok(Motivo::Limite),
+            1 => Ok(Motivo::Reservas),
+            motivo => Err(DecodeError::InvalidMotivo(motivo)),
+        }
+    }
+}
+
+impl Encode for Rejeicao {
+    const SIZE: usize = 8 + 8 + SIZE_DESCRIPTION + 8 + 4 + 4;
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend({ self.seq }.to_le_bytes());
+        out.extend({ self.value }.to_le_bytes());
+        put_description(out, &{ self.transacao_description });
+        put_timestamp(out, self.timestap);
+        out.extend((self.motivo as u32).to_le_bytes());
+        out.extend({ self.transferencia }.to_le_bytes());
+    }
+}
+
+impl Decode for Rejeicao {
+    fn decode(input: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Rejeicao {
+            seq: input.u64()?,
+            value: input.i64()?,
+            transacao_description: input.description()?,
+            timestap: input.timestamp()?,
+            motivo: Motivo::from_u32(input.u32()?)?,
+            transferencia: input.u32()?,
+        })
+    }
+}
+
+impl Status {
+    fn from_u8(status: u8) -> Result<Status, DecodeError> {
+        Ok(match status {
+            0 => Status::Ok,
+            1 => Status::NotFound,
+            2 => Status::LimitExceeded,
+            3 => Status::Malformed,
+            4 => Status::StorageError,
+            5 => Status::Busy,
+            6 => Status::Conflict,
+            status => return Err(DecodeError::InvalidStatus(status)),
+        })
+    }
+}
+
+// A reply of the DB: the `request_id` of the operation, its status, then the payload when
+// the status is `Status::Ok`.
+pub fn envelope(request_id: u64, status: Status, payload: &[u8]) -> Vec<u8> {
+    debug_assert!(status == Status::Ok || payload.is_empty());
+    let mut out = Vec::with_capacity(8 + 1 + payload.len());
+    out.extend(request_id.to_le_bytes());
+    out.push(status as u8);
+    out.extend_from_slice(payload);
+    out
+}
+
+pub fn open_envelope(buff: &[u8]) -> Result<(u64, Status, &[u8]), DecodeError> {
+    let mut input = Reader { buff };
+    let request_id = input.u64()?;
+    let status = Status::from_u8(input.u8()?)?;
+    if status != Status::Ok && !input.buff.is_empty() {
+        return Err(DecodeError::TrailingBytes(input.buff.len()));
+    }
+    Ok((request_id, status, input.buff))
+}
+
+// The request id of an operation, read even when the rest does not decode.
+pub fn request_id_of(buff: &[u8]) -> u64 {
+    Reader { buff }.u64().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn description(text: &str) -> BufferDescription {
+        let mut description = ['\0'; NCHAR_DESCRIPTION];
+        for (indx, ch) in text.chars().enumerate() {
+            description[indx] = ch;
+        }
+        description
+    }
+
+    fn timestamp() -> std::time::SystemTime {
+        UNIX_EPOCH + Duration::from_nanos(1_706_745_599_123_456_789)
+    }
+
+    // Decoding the bytes of `value` and encoding it again gives the same bytes.
+    fn round_trip<T: Encode + Decode>(value: &T) -> T {
+        let bytes = value.to_bytes();
+        assert_eq!(bytes.len(), T::SIZE);
+        let decoded = T::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.to_bytes(), bytes);
+        decoded
+    }
+
+    fn ledger(seq: u64) -> TransacaoLedger {
+        TransacaoLedger {
+            seq,
+            value: -1000,
+            transacao_description: description("aluguel"),
+            timestap: timestamp(),
+            estorno: 3,
+            transferencia: 2,
+            update_limit: false,
+            limite: 0,
+        }
+    }
+
+    fn rejeicao(seq: u64) -> Rejeicao {
+        Rejeicao {
+            seq,
+            value: -5000,
+            transacao_description: description("é caro"),
+            timestap: timestamp(),
+            motivo: Motivo::Reservas,
+            transferencia: 0,
+        }
+    }
+
+    #[test]
+    fn operation_round_trip() {
+        let op = Operation {
+            request_id: 42 << 32 | 7,
+            kind: OperationKind::Transferencia,
+            id: 1,
+            transacao: Transacao {
+                value: 100,
+                transacao_description: description("pix"),
+                timestap: timestamp(),
+            },
+            pagina: Pagina {
+                limit: 10,
+                before: 99,
+            },
+            filtro: Filtro {
+                ativo: true,
+                tipo: b'd',
+                de: 1,
+                ate: 2,
+            },
+            em: 3,
+            novo_cliente: NovoCliente {
+                limite: 4,
+                saldo: -5,
+            },
+            estorno: 6,
+            para: 2,
+            limite: 7,
+            reserva: 8,
+            idempotency_key: [b'k'; SIZE_IDEMPOTENCY_KEY],
+        };
+        let decoded = round_trip(&op);
+        assert_eq!({ decoded.request_id }, 42 << 32 | 7);
+        assert!(matches!(decoded.kind, OperationKind::Transferencia));
+        assert_eq!({ decoded.para }, 2);
+        assert_eq!({ decoded.transacao.timestap }, timestamp());
+        assert_eq!(
+            { decoded.transacao.transacao_description },
+            description("pix")
+        );
+    }
+
+    #[test]
+    fn replies_round_trip() {
+        let mut extrato = Extrato {
+            total: -10,
+            limite: 1000,
+            disponivel: 990,
+            transacoes: [TransacaoExtrato {
+                isvalid: false,
+                value: 0,
+                transacao_description: ['\0'; NCHAR_DESCRIPTION],
+                timestap: UNIX_EPOCH,
+            }; NTRANSACOES],
+            mais: true,
+        };
+        extrato.transacoes[0] = TransacaoExtrato {
+            isvalid: true,
+            value: -10,
+            transacao_description: description("café"),
+            timestap: timestamp(),
+        };
+        let decoded = round_trip(&extrato);
+        assert!(decoded.mais);
+        assert_eq!({ decoded.transacoes[0].value }, -10);
+
+        let decoded = round_trip(&TransacaoReturn {
+            limite: 1000,
+            saldo: -10,
+            disponivel: 990,
+        });
+        assert_eq!({ decoded.disponivel }, 990);
+
+        let decoded = round_trip(&ClienteReturn {
+            id: 6,
+            limite: 1000,
+            saldo: -10,
+        });
+        assert_eq!({ decoded.id }, 6);
+
+        let decoded = round_trip(&HoldReturn {
+            reserva: 9,
+            limite: 1000,
+            saldo: -10,
+            disponivel: 890,
+        });
+        assert_eq!({ decoded.reserva }, 9);
+
+        let decoded = round_trip(&SaldoReturn {
+            saldo: -10,
+            limite: 500,
+        });
+        assert_eq!({ decoded.limite }, 500);
+
+        let mut novo_limite = ledger(4);
+        novo_limite.update_limit = true;
+        novo_limite.limite = 5000;
+        let decoded = round_trip(&novo_limite);
+        assert!(decoded.update_limit);
+        assert_eq!({ decoded.limite }, 5000);
+    }
+
+    #[test]
+    fn pagina_round_trip() {
+        let header = PaginaHeader {
+            ntransacoes: 2,
+            next_before: 4,
+        };
+        let bytes = encode_pagina(&header, &[ledger(6), ledger(5)]);
+        let (decoded, transacoes) = decode_pagina::<TransacaoLedger>(&bytes).unwrap();
+        assert_eq!({ decoded.next_before }, 4);
+        assert_eq!(transacoes.len(), 2);
+        assert_eq!({ transacoes[1].seq }, 5);
+        assert_eq!(encode_pagina(&decoded, &transacoes), bytes);
+
+        let bytes = encode_pagina(&header, &[rejeicao(2), rejeicao(1)]);
+        let (_, rejeicoes) = decode_pagina::<Rejeicao>(&bytes).unwrap();
+        assert_eq!({ rejeicoes[0].motivo }, Motivo::Reservas);
+        assert_eq!(
+            { rejeicoes[1].transacao_description },
+            description("é caro")
+        );
+
+        let empty = PaginaHeader {
+            ntransacoes: 0,
+            next_before: 0,
+        };
+        let bytes = encode_pagina::<Rejeicao>(&empty, &[]);
+        assert!(decode_pagina::<Rejeicao>(&bytes).unwrap().1.is_empty());
+    }
+
+    #[test]
+    fn envelope_round_trip() {
+        let payload = SaldoReturn {
+            saldo: 1,
+            limite: 2,
+        }
+        .to_bytes();
+        let bytes = envelope(77, Status::Ok, &payload);
+        assert_eq!(open_envelope(&bytes), Ok((77, Status::Ok, &payload[..])));
+        let bytes = envelope(78, Status::Conflict, &[]);
+        assert_eq!(open_envelope(&bytes), Ok((78, Status::Conflict, &[][..])));
+        assert_eq!(request_id_of(&bytes), 78);
+    }
+
+    #[test]
+    fn rejects_short_and_long_buffers() {
+        let bytes = ClienteReturn {
+            id: 1,
+            limite: 2,
+            saldo: 3,
+        }
+        .to_bytes();
+        for len in 0..bytes.len() {
+            assert_eq!(
+                ClienteReturn::from_bytes(&bytes[..len]).err(),
+                Some(DecodeError::Truncated)
+            );
+        }
+        let mut long = bytes.clone();
+        long.push(0);
+        assert_eq!(
+            ClienteReturn::from_bytes(&long).err(),
+            Some(DecodeError::TrailingBytes(1))
+        );
+
+        let header = PaginaHeader {
+            ntransacoes: 1,
+            next_before: 0,
+        };
+        let bytes = encode_pagina(&header, &[ledger(1)]);
+        assert_eq!(
+            decode_pagina::<TransacaoLedger>(&bytes[..bytes.len() - 1]).err(),
+            Some(DecodeError::Truncated)
+        );
+        let mut long = bytes.clone();
+        long.extend([0; 3]);
+        assert_eq!(
+            decode_pagina::<TransacaoLedger>(&long).err(),
+            Some(DecodeError::TrailingBytes(3))
+        );
+
+        assert_eq!(open_envelope(&[0; 8]), Err(DecodeError::Truncated));
+        let mut bytes = envelope(1, Status::NotFound, &[]);
+        bytes.push(0);
+        assert_eq!(open_envelope(&bytes), Err(DecodeError::TrailingBytes(1)));
+        assert_eq!(request_id_of(&[1, 2]), 0);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let op: Operation = unsafe { std::mem::zeroed() };
+        let mut bytes = op.to_bytes();
+        bytes[8..12].copy_from_slice(&12u32.to_le_bytes());
+        assert_eq!(
+            Operation::from_bytes(&bytes).err(),
+            Some(DecodeError::InvalidKind(12))
+        );
+
+        let mut bytes = envelope(1, Status::Ok, &[]);
+        bytes[8] = 7;
+        assert_eq!(open_envelope(&bytes), Err(DecodeError::InvalidStatus(7)));
+
+        let mut bytes = rejeicao(1).to_bytes();
+        let motivo = Rejeicao::SIZE - 8;
+        bytes[motivo..motivo + 4].copy_from_slice(&2u32.to_le_bytes());
+        assert_eq!(
+            Rejeicao::from_bytes(&bytes).err(),
+            Some(DecodeError::InvalidMotivo(2))
+        );
+
+        let mut bytes = ledger(1).to_bytes();
+        bytes[TransacaoLedger::SIZE - 9] = 2;
+        assert_eq!(
+            TransacaoLedger::from_bytes(&bytes).err(),
+            Some(DecodeError::InvalidBool(2))
+        );
+
+        // A surrogate is not a `char`.
+        let mut bytes = ledger(1).to_bytes();
+        bytes[16..20].copy_from_slice(&0xd800u32.to_le_bytes());
+        assert_eq!(
+            TransacaoLedger::from_bytes(&bytes).err(),
+            Some(DecodeError::InvalidChar(0xd800))
+        );
+
+        let header = PaginaHeader {
+            ntransacoes: MAX_PAGINA as u32 + 1,
+            next_before: 0,
+        };
+        assert_eq!(
+            decode_pagina::<Rejeicao>(&header.to_bytes()).err(),
+            Some(DecodeError::TooManyItems(MAX_PAGINA as u32 + 1))
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use crate::dump::Format;
+use crate::storage::StorageKind;
+use database::PORT_DB;
+use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::exit;
+
+pub enum Command {
+    Serve,
+    // Checks the data files offline, `repair` writes the fixes back.
+    Fsck { repair: bool },
+    // Writes every client to `file`, or loads them from it.
+    Export,
+    Import,
+}
+
+pub struct Config {
+    pub command: Command,
+    pub data_dir: PathBuf,
+    pub seed: Option<String>,
+    // Serve even when existing clients were not created as the seed says.
+    pub accept_seed_drift: bool,
+    pub format: Format,
+    pub file: Option<PathBuf>,
+    pub port: u16,
+    // Addresses the records are streamed to, when this is the primary.
+    pub replicas: Vec<SocketAddr>,
+    // Address of the primary, when this is a replica.
+    pub follow: Option<SocketAddr>,
+    // Threads the clients are split across, client `id` belongs to `id % workers`.
+    pub workers: usize,
+    // Where the pages of `database.db` are kept.
+    pub storage: StorageKind,
+}
+
+impl Config {
+    pub fn from_args() -> Config {
+        let mut config = Config {
+            command: Command::Serve,
+            data_dir: PathBuf::from("."),
+            seed: None,
+            accept_seed_drift: false,
+            format: Format::Json,
+            file: None,
+            port: PORT_DB,
+            replicas: Vec::new(),
+            follow: None,
+            workers: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            storage: StorageKind::File,
+        };
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--data-dir" => {
+                    let path = args.next();
+                    if path.is_none() {
+                        eprintln!("--data-dir requires a path");
+                        exit(1);
+                    }
+                    config.data_dir = PathBuf::from(path.unwrap());
+                }
+                "--seed" => {
+                    let path = args.next();
+                    if path.is_none() {
+                        eprintln!("--seed requires a path");
+                        exit(1);
+                    }
+                    config.seed = path;
+                }
+                "--accept-seed-drift" => config.accept_seed_drift = true,
+                "--format" => match args.next().as_deref() {
+                    Some("json") => config.format = Format::Json,
+                    Some("csv") => config.format = Format::Csv,
+                    _ => {
+                        eprintln!("--format requires json or csv");
+                        exit(1);
+                    }
+                },
+                "--file" => {
+                    let path = args.next();
+                    if path.is_none() {
+                        eprintln!("--file requires a path");
+                        exit(1);
+                    }
+                    config.file = path.map(PathBuf::from);
+                }
+                "--port" => {
+                    let port = args.next().map(|port| port.parse::<u16>());
+                    if !matches!(port, Some(Ok(_))) {
+                        eprintln!("--port requires a port number");
+                        exit(1);
+                    }
+                    config.port = port.unwrap().unwrap();
+                }
+                "--workers" => {
+                    let workers = args.next().map(|workers| workers.parse::<usize>());
+                    if !matches!(workers, Some(Ok(1..))) {
+                        eprintln!("--workers requires a number of threads");
+                        exit(1);
+                    }
+                    config.workers = workers.unwrap().unwrap();
+                }
+                "--storage" => match args.next().as_deref() {
+                    Some("file") => config.storage = StorageKind::File,
+                    Some("memory") => config.storage = StorageKind::Memory,
+                    Some("mmap") => config.storage = StorageKind::Mmap,
+                    _ => {
+                        eprintln!("--storage requires file, memory or mmap");
+                        exit(1);
+                    }
+                },
+                "--replica" | "--follow" => {
+                    let addr = args.next().map(|addr| addr.parse::<SocketAddr>());
+                    if !matches!(addr, Some(Ok(_))) {
+                        eprintln!("{arg} requires an address like 127.0.0.1:7001");
+                        exit(1);
+                    }
+                    let addr = addr.unwrap().unwrap();
+                    if arg == "--replica" {
+                        config.replicas.push(addr);
+                    } else {
+                        config.follow = Some(addr);
+                    }
+                }
+                "export" => config.command = Command::Export,
+                "import" => config.command = Command::Import,
+                "fsck" => config.command = Command::Fsck { repair: false },
+                "--repair" => {
+                    if !matches!(config.command, Command::Fsck { .. }) {
+                        eprintln!("--repair is only valid after fsck");
+                        exit(1);
+                    }
+                    config.command = Command::Fsck { repair: true };
+                }
+                _ => {
+                    eprintln!("invalid argument: {arg}");
+                    exit(1);
+                }
+            }
+        }
+        if config.follow.is_some() && (!config.replicas.is_empty() || config.seed.is_some()) {
+            eprintln!("a replica takes its clients from the primary, --replica and --seed are not allowed with --follow");
+            exit(1);
+        }
+        if config.accept_seed_drift && config.seed.is_none() {
+            eprintln!("--accept-seed-drift is only valid with --seed");
+            exit(1);
+        }
+        if config.storage == StorageKind::Memory && !matches!(config.command, Command::Serve) {
+            eprintln!("--storage memory keeps nothing on disk, it is only valid to serve");
+            exit(1);
+        }
+        if matches!(config.command, Command::Export | Command::Import) && config.file.is_none() {
+            eprintln!("export and import require --file");
+            exit(1);
+        }
+        config
+    }
+}
@@ -0,0 +1,741 @@
+use crate::client::{Client, MAX_HOLDS};
+use crate::ledger::Link;
+use crate::pager::Pager;
+use chrono::{DateTime, SecondsFormat, Utc};
+use database::{Transacao, NCHAR_DESCRIPTION, SIZE_IDEMPOTENCY_KEY};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, ErrorKind};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+// Same fields as the transactions of the HTTP API.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DumpTransacao {
+    valor: u64,
+    tipo: String,
+    descricao: String,
+    realizada_em: String,
+    // Position in the ledger (from 1) of the transaction this one reverses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    estorno_de: Option<u64>,
+    // Id of the other client of a transfer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    transferencia: Option<u32>,
+    // New limite of a change of the limite, which has valor 0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    novo_limite: Option<i64>,
+}
+
+// An open hold, still to be captured or released.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct DumpReserva {
+    reserva: u64,
+    valor: i64,
+}
+
+// One client with its whole ledger, oldest transaction first.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DumpClient {
+    id: u32,
+    limite: i64,
+    saldo: i64,
+    // The limite before the first change in `transacoes`, `limite` when it is missing.
+    #[serde(default)]
+    limite_inicial: Option<i64>,
+    // The last reserva given out, closed ones included.
+    #[serde(default)]
+    ultima_reserva: u64,
+    #[serde(default)]
+    reservas: Vec<DumpReserva>,
+    #[serde(default)]
+    transacoes: Vec<DumpTransacao>,
+}
+
+pub type DumpEntry = (Transacao, Link);
+
+const CSV_HEADER: &str =
+    "id,limite,saldo,limite_inicial,ultima_reserva,reservas,valor,tipo,descricao,realizada_em,estorno_de,transferencia,novo_limite";
+
+fn invalid(reason: String) -> io::Error {
+    eprintln!("DB.Dump: Invalid dump: {reason}");
+    io::Error::new(ErrorKind::InvalidData, reason)
+}
+
+impl From<&DumpEntry> for DumpTransacao {
+    fn from((transacao, link): &DumpEntry) -> Self {
+        let value = transacao.value;
+        let mut descricao = String::new();
+        for ch in transacao.transacao_description {
+            if ch == '\0' {
+                break;
+            }
+            descricao.push(ch);
+        }
+        let date: DateTime<Utc> = { transacao.timestap }.into();
+        DumpTransacao {
+            valor: value.unsigned_abs(),
+            tipo: if value < 0 { "d" } else { "c" }.into(),
+            descricao,
+            realizada_em: date.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            estorno_de: Some(link.estorno).filter(|estorno| *estorno != 0),
+            transferencia: Some(link.transferencia).filter(|id| *id != 0),
+            novo_limite: Some(link.limite).filter(|_| link.update_limit),
+        }
+    }
+}
+
+impl TryFrom<&DumpTransacao> for Transacao {
+    type Error = String;
+
+    fn try_from(dump: &DumpTransacao) -> Result<Self, Self::Error> {
+        let valor =
+            i64::try_from(dump.valor).map_err(|_| format!("valor {} too big", dump.valor))?;
+        let value = match dump.tipo.as_str() {
+            "c" => valor,
+            "d" => -valor,
+            tipo => return Err(format!("invalid tipo {tipo:?}")),
+        };
+        if dump.descricao.chars().count() > NCHAR_DESCRIPTION || dump.descricao.contains('\0') {
+            return Err(format!("invalid descricao {:?}", dump.descricao));
+        }
+        let date = DateTime::parse_from_rfc3339(&dump.realizada_em)
+            .map_err(|e| format!("invalid realizada_em {:?}: {e}", dump.realizada_em))?;
+        let timestap = SystemTime::from(date);
+        if timestap < UNIX_EPOCH {
+            return Err(format!("realizada_em {} before 1970", dump.realizada_em));
+        }
+        let mut transacao = Transacao {
+            value,
+            transacao_description: ['\0'; NCHAR_DESCRIPTION],
+            timestap,
+        };
+        for (indx, ch) in dump.descricao.chars().enumerate() {
+            transacao.transacao_description[indx] = ch;
+        }
+        Ok(transacao)
+    }
+}
+
+pub fn export(pager: &Pager, clients: &HashMap<u32, Client>, format: Format) -> io::Result<String> {
+    let mut ids: Vec<&u32> = clients.keys().collect();
+    ids.sort();
+    let mut dump = Vec::with_capacity(ids.len());
+    for id in ids {
+        let client = &clients[id];
+        let mut transacoes = Vec::with_capacity(client.ledger.len() as usize);
+        for seq in 1..=client.ledger.len() {
+            transacoes.push((&client.ledger.read(pager, seq)?).into());
+        }
+        dump.push(DumpClient {
+            id: client.id,
+            limite: client.limite,
+            saldo: client.saldo,
+            limite_inicial: Some(client.limite_inicial),
+            ultima_reserva: client.last_hold(),
+            reservas: client
+                .holds()
+                .into_iter()
+                .map(|(reserva, valor)| DumpReserva { reserva, valor })
+                .collect(),
+            transacoes,
+        });
+    }
+    match format {
+        Format::Json => Ok(serde_json::to_string_pretty(&dump).unwrap()),
+        Format::Csv => Ok(to_csv(&dump)),
+    }
+}
+
+// Parses and validates a dump, nothing is written if any client is invalid.
+pub fn read_dump(text: &str, format: Format) -> io::Result<Vec<(DumpClient, Vec<DumpEntry>)>> {
+    let dump = match format {
+        Format::Json => serde_json::from_str::<Vec<DumpClient>>(text).map_err(|e| e.to_string()),
+        Format::Csv => from_csv(text),
+    };
+    if let Err(e) = dump {
+        return Err(invalid(e));
+    }
+    let mut ids = HashSet::new();
+    let mut out = Vec::new();
+    for client in dump.unwrap() {
+        if client.id == 0 {
+            return Err(invalid("id 0 is reserved".into()));
+        }
+        if !ids.insert(client.id) {
+            return Err(invalid(format!("id {} is repeated", client.id)));
+        }
+        if client.limite < 0 || client.saldo < -client.limite {
+            return Err(invalid(format!(
+                "client {} has saldo under its limite",
+                client.id
+            )));
+        }
+        let mut transacoes: Vec<DumpEntry> = Vec::with_capacity(client.transacoes.len());
+        let mut reversed = HashSet::new();
+        for (indx, dump_transacao) in client.transacoes.iter().enumerate() {
+            let transacao = Transacao::try_from(dump_transacao);
+            if let Err(e) = transacao {
+                return Err(invalid(format!("client {}: {e}", client.id)));
+            }
+            // A reversal points to an earlier plain transaction, reversed only once.
+            let link = Link {
+                estorno: dump_transacao.estorno_de.unwrap_or(0),
+                transferencia: dump_transacao.transferencia.unwrap_or(0),
+                update_limit: dump_transacao.novo_limite.is_some(),
+                limite: dump_transacao.novo_limite.unwrap_or(0),
+            };
+            let estorno = link.estorno;
+            if estorno != 0
+                && (estorno > indx as u64
+                    || link.transferencia != 0
+                    || transacoes[estorno as usize - 1].1 != Link::default()
+                    || !reversed.insert(estorno))
+            {
+                return Err(invalid(format!(
+                    "client {}: invalid estorno_de {estorno}",
+                    client.id
+                )));
+            }
+            if link.transferencia == client.id {
+                return Err(invalid(format!("client {}: transfer to itself", client.id)));
+            }
+            if link.update_limit
+                && (link.limite < 0
+                    || dump_transacao.valor != 0
+                    || link.estorno != 0
+                    || link.transferencia != 0)
+            {
+                return Err(invalid(format!(
+                    "client {}: invalid change of the limite",
+                    client.id
+                )));
+            }
+            transacoes.push((transacao.unwrap(), link));
+        }
+        // The client is created with its limite_inicial, the ledger must end at its limite.
+        let limite_inicial = client.limite_inicial.unwrap_or(client.limite);
+        if limite_inicial < 0 {
+            return Err(invalid(format!(
+                "client {} has a negative limite_inicial",
+                client.id
+            )));
+        }
+        let last_limite = transacoes
+            .iter()
+            .rev()
+            .find(|(_, link)| link.update_limit)
+            .map_or(limite_inicial, |(_, link)| link.limite);
+        if last_limite != client.limite {
+            return Err(invalid(format!(
+                "client {}: last novo_limite, or limite_inicial, is not its limite",
+                client.id
+            )));
+        }
+        // The holds must fit in the limite, like when they were opened.
+        let mut reservas = HashSet::new();
+        let mut disponivel = Some(client.saldo);
+        for reserva in client.reservas.iter() {
+            if reserva.reserva == 0
+                || reserva.reserva > client.ultima_reserva
+                || reserva.valor <= 0
+                || !reservas.insert(reserva.reserva)
+            {
+                return Err(invalid(format!(
+                    "client {}: invalid reserva {}",
+                    client.id, reserva.reserva
+                )));
+            }
+            disponivel = disponivel.and_then(|disponivel| disponivel.checked_sub(reserva.valor));
+        }
+        if client.reservas.len() > MAX_HOLDS
+            || disponivel.is_none_or(|disponivel| disponivel < -client.limite)
+        {
+            return Err(invalid(format!(
+                "client {} has reservas over its limite",
+                client.id
+            )));
+        }
+        out.push((client, transacoes));
+    }
+    // Importing one side of a transfer without the other would create money.
+    for (client, transacoes) in out.iter() {
+        let missing = transacoes
+            .iter()
+            .find(|(_, link)| link.transferencia != 0 && !ids.contains(&{ link.transferencia }));
+        if let Some((_, link)) = missing {
+            return Err(invalid(format!(
+                "client {}: transfer with client {}, who is not in the dump",
+                client.id,
+                { link.transferencia }
+            )));
+        }
+    }
+    Ok(out)
+}
+
+// Creates every client of the dump with its ledger. Existing ids are refused, so a dump
+// is only ever loaded once. Nothing goes through the WAL, so the caller works on a copy
+// of the data file and only puts it in place once it is checkpointed.
+pub fn import(
+    pager: &Pager,
+    clients: &mut HashMap<u32, Client>,
+    dump: Vec<(DumpClient, Vec<DumpEntry>)>,
+) -> io::Result<()> {
+    for (client, _) in dump.iter() {
+        if clients.contains_key(&client.id) {
+            return Err(invalid(format!("client {} already exists", client.id)));
+        }
+    }
+    let mut lsn = clients.values().map(|client| client.lsn).max().unwrap_or(0);
+    for (dump_client, transacoes) in dump {
+        // The ledger is replayed on top of the saldo the client started with.
+        let total = transacoes.iter().try_fold(0i64, |total, (transacao, _)| {
+            total.checked_add(transacao.value)
+        });
+        let saldo = total.and_then(|total| dump_client.saldo.checked_sub(total));
+        if saldo.is_none() {
+            return Err(invalid(format!(
+                "client {} transactions overflow",
+                dump_client.id
+            )));
+        }
+        lsn += 1;
+        let mut client = Client::new(
+            pager,
+            dump_client.id,
+            dump_client.limite_inicial.unwrap_or(dump_client.limite),
+            saldo.unwrap(),
+            lsn,
+        )?;
+        for (transacao, link) in transacoes {
+            lsn += 1;
+            client.apply_transacao(pager, lsn, transacao, [0; SIZE_IDEMPOTENCY_KEY], link)?;
+        }
+        let holds: Vec<(u64, i64)> = dump_client
+            .reservas
+            .iter()
+            .map(|reserva| (reserva.reserva, reserva.valor))
+            .collect();
+        lsn += 1;
+        client.restore_holds(lsn, dump_client.ultima_reserva, &holds);
+        println!(
+            "DB.Dump: Client {} imported with {} transactions",
+            client.id,
+            client.ledger.len()
+        );
+        clients.insert(client.id, client);
+    }
+    Ok(())
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// One row per transaction, a client without transactions gets a row with them empty.
+// The open holds go in one field of every row, as `reserva:valor` separated by `;`.
+fn to_csv(dump: &[DumpClient]) -> String {
+    let mut out = String::from(CSV_HEADER);
+    out.push('\n');
+    for client in dump {
+        let reservas: Vec<String> = client
+            .reservas
+            .iter()
+            .map(|reserva| format!("{}:{}", reserva.reserva, reserva.valor))
+            .collect();
+        let prefix = format!(
+            "{},{},{},{},{},{}",
+            client.id,
+            client.limite,
+            client.saldo,
+            client
+                .limite_inicial
+                .map(|limite| limite.to_string())
+                .unwrap_or_default(),
+            client.ultima_reserva,
+            reservas.join(";")
+        );
+        if client.transacoes.is_empty() {
+            out.push_str(&format!("{prefix},,,,,,,\n"));
+        }
+        for transacao in client.transacoes.iter() {
+            out.push_str(&format!(
+                "{prefix},{},{},{},{},{},{},{}\n",
+                transacao.valor,
+                csv_field(&transacao.tipo),
+                csv_field(&transacao.descricao),
+                csv_field(&transacao.realizada_em),
+                transacao
+                    .estorno_de
+                    .map(|seq| seq.to_string())
+                    .unwrap_or_default(),
+                transacao
+                    .transferencia
+                    .map(|id| id.to_string())
+                    .unwrap_or_default(),
+                transacao
+                    .novo_limite
+                    .map(|limite| limite.to_string())
+                    .unwrap_or_default()
+            ));
+        }
+    }
+    out
+}
+
+fn parse_csv(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match (quoted, ch) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (true, ch) => field.push(ch),
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => row.push(std::mem::take(&mut field)),
+            (false, '\r') if chars.peek() == Some(&'\n') => {}
+            (false, '\n') => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            (false, ch) => field.push(ch),
+        }
+    }
+    if quoted {
+        return Err("unterminated quoted field".into());
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+fn from_csv(text: &str) -> Result<Vec<DumpClient>, String> {
+    let rows = parse_csv(text)?;
+    if rows.first().map(|row| row.join(",")) != Some(CSV_HEADER.into()) {
+        return Err(format!("the first line must be {CSV_HEADER}"));
+    }
+    let mut dump: Vec<DumpClient> = Vec::new();
+    for (line, row) in rows.into_iter().enumerate().skip(1) {
+        let line = line + 1;
+        if row.len() != 13 {
+            return Err(format!(
+                "line {line}: expected 13 fields, found {}",
+                row.len()
+            ));
+        }
+        let id = row[0]
+            .parse()
+            .map_err(|_| format!("line {line}: invalid id"))?;
+        let limite = row[1]
+            .parse()
+            .map_err(|_| format!("line {line}: invalid limite"))?;
+        let saldo = row[2]
+            .parse()
+            .map_err(|_| format!("line {line}: invalid saldo"))?;
+        let limite_inicial = match row[3].as_str() {
+            "" => None,
+            limite => Some(
+                limite
+                    .parse()
+                    .map_err(|_| format!("line {line}: invalid limite_inicial"))?,
+            ),
+        };
+        let ultima_reserva = match row[4].as_str() {
+            "" => 0,
+            reserva => reserva
+                .parse()
+                .map_err(|_| format!("line {line}: invalid ultima_reserva"))?,
+        };
+        let mut reservas = Vec::new();
+        for reserva in row[5].split(';').filter(|reserva| !reserva.is_empty()) {
+            let parsed = reserva
+                .split_once(':')
+                .and_then(|(reserva, valor)| Some((reserva.parse().ok()?, valor.parse().ok()?)));
+            if parsed.is_none() {
+                return Err(format!("line {line}: invalid reserva {reserva:?}"));
+            }
+            let (reserva, valor) = parsed.unwrap();
+            reservas.push(DumpReserva { reserva, valor });
+        }
+        let client = match dump.last_mut() {
+            Some(client) if client.id == id => {
+                if client.limite != limite
+                    || client.saldo != saldo
+                    || client.limite_inicial != limite_inicial
+                    || client.ultima_reserva != ultima_reserva
+                    || client.reservas != reservas
+                {
+                    return Err(format!(
+                        "line {line}: client {id} changed limite, saldo or reservas"
+                    ));
+                }
+                client
+            }
+            _ => {
+                dump.push(DumpClient {
+                    id,
+                    limite,
+                    saldo,
+                    limite_inicial,
+                    ultima_reserva,
+                    reservas,
+                    transacoes: Vec::new(),
+                });
+                dump.last_mut().unwrap()
+            }
+        };
+        if row[6..].iter().all(|field| field.is_empty()) {
+            continue;
+        }
+        let valor = row[6]
+            .parse()
+            .map_err(|_| format!("line {line}: invalid valor"))?;
+        let estorno_de = match row[10].as_str() {
+            "" => None,
+            seq => Some(
+                seq.parse()
+                    .map_err(|_| format!("line {line}: invalid estorno_de"))?,
+            ),
+        };
+        let transferencia = match row[11].as_str() {
+            "" => None,
+            id => Some(
+                id.parse()
+                    .map_err(|_| format!("line {line}: invalid transferencia"))?,
+            ),
+        };
+        let novo_limite = match row[12].as_str() {
+            "" => None,
+            limite => Some(
+                limite
+                    .parse()
+                    .map_err(|_| format!("line {line}: invalid novo_limite"))?,
+            ),
+        };
+        client.transacoes.push(DumpTransacao {
+            valor,
+            tipo: row[7].clone(),
+            descricao: row[8].clone(),
+            realizada_em: row[9].clone(),
+            estorno_de,
+            transferencia,
+            novo_limite,
+        });
+    }
+    Ok(dump)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageKind;
+    use std::path::Path;
+
+    // A client with a reversal, a change of the limite, two open holds and a transfer to a
+    // client without any other transaction, plus one without transactions at all.
+    const DUMP: &str = r#"[
+        {"id": 1, "limite": 2000, "saldo": 400, "limite_inicial": 1000, "ultima_reserva": 3,
+         "reservas": [{"reserva": 1, "valor": 300}, {"reserva": 3, "valor": 50}], "transacoes": [
+            {"valor": 500, "tipo": "c", "descricao": "salário", "realizada_em": "2024-01-01T10:00:00Z"},
+            {"valor": 200, "tipo": "d", "descricao": "a, \"b\"", "realizada_em": "2024-01-02T10:00:00.5Z"},
+            {"valor": 200, "tipo": "c", "descricao": "estorno", "realizada_em": "2024-01-03T10:00:00Z", "estorno_de": 2},
+            {"valor": 0, "tipo": "c", "descricao": "", "realizada_em": "2024-01-04T10:00:00Z", "novo_limite": 2000},
+            {"valor": 100, "tipo": "d", "descricao": "aluguel", "realizada_em": "2024-01-05T10:00:00Z", "transferencia": 2}
+        ]},
+        {"id": 2, "limite": 500, "saldo": 100, "transacoes": [
+            {"valor": 100, "tipo": "c", "descricao": "aluguel", "realizada_em": "2024-01-05T10:00:00Z", "transferencia": 1}
+        ]},
+        {"id": 3, "limite": 100, "saldo": -50}
+    ]"#;
+
+    fn load(text: &str, format: Format) -> (Pager, HashMap<u32, Client>) {
+        let pager = Pager::open(Path::new("dump-test"), StorageKind::Memory).unwrap();
+        let mut clients = HashMap::new();
+        import(&pager, &mut clients, read_dump(text, format).unwrap()).unwrap();
+        (pager, clients)
+    }
+
+    fn round_trip(text: &str, format: Format) -> String {
+        let (pager, clients) = load(text, format);
+        export(&pager, &clients, format).unwrap()
+    }
+
+    fn refused(text: &str, format: Format) -> bool {
+        matches!(read_dump(text, format), Err(e) if e.kind() == ErrorKind::InvalidData)
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let (pager, clients) = load(DUMP, Format::Json);
+        let client = &clients[&1];
+        assert_eq!(
+            (client.limite, client.limite_inicial, client.saldo),
+            (2000, 1000, 400)
+        );
+        assert_eq!(client.ledger.len(), 5);
+        assert_eq!(client.holds(), [(1, 300), (3, 50)]);
+        assert_eq!((client.last_hold(), client.disponivel()), (3, 50));
+        assert_eq!((clients[&3].saldo, clients[&3].ledger.len()), (-50, 0));
+
+        let exported = export(&pager, &clients, Format::Json).unwrap();
+        assert_eq!(round_trip(&exported, Format::Json), exported);
+        let dump: Vec<DumpClient> = serde_json::from_str(&exported).unwrap();
+        let transacoes = &dump[0].transacoes;
+        assert_eq!(transacoes[1].descricao, "a, \"b\"");
+        assert_eq!(transacoes[1].realizada_em, "2024-01-02T10:00:00.500Z");
+        assert_eq!(transacoes[2].estorno_de, Some(2));
+        assert_eq!(transacoes[3].novo_limite, Some(2000));
+        assert_eq!(transacoes[4].transferencia, Some(2));
+        assert_eq!(dump[1].limite_inicial, Some(500));
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let json = round_trip(DUMP, Format::Json);
+        let (pager, clients) = load(&json, Format::Json);
+        let csv = export(&pager, &clients, Format::Csv).unwrap();
+        assert_eq!(round_trip(&csv, Format::Csv), csv);
+
+        // Going through the CSV loses nothing of the JSON.
+        let (pager, clients) = load(&csv, Format::Csv);
+        assert_eq!(export(&pager, &clients, Format::Json).unwrap(), json);
+    }
+
+    #[test]
+    fn rejects_malformed_rows() {
+        assert!(refused("[{\"id\": 1, \"limite\": 10}]", Format::Json));
+        assert!(refused("id,limite,saldo\n1,10,0\n", Format::Csv));
+        assert!(refused(&format!("{CSV_HEADER}\n1,10,0,,,,\n"), Format::Csv));
+        assert!(refused(
+            &format!("{CSV_HEADER}\n1,10,0,,0,,abc,c,x,2024-01-01T00:00:00Z,,,\n"),
+            Format::Csv
+        ));
+        assert!(refused(
+            &format!("{CSV_HEADER}\n1,10,0,,0,,5,x,x,2024-01-01T00:00:00Z,,,\n"),
+            Format::Csv
+        ));
+        assert!(refused(
+            &format!("{CSV_HEADER}\n1,10,0,,0,,5,c,x,ontem,,,\n"),
+            Format::Csv
+        ));
+        assert!(refused(
+            &format!("{CSV_HEADER}\n1,10,0,,0,,5,c,\"x,2024-01-01T00:00:00Z,,,\n"),
+            Format::Csv
+        ));
+        // The rows of a client must agree on its limite and saldo.
+        assert!(refused(
+            &format!("{CSV_HEADER}\n1,10,0,,0,,5,c,x,2024-01-01T00:00:00Z,,,\n1,10,5,,0,,5,c,y,2024-01-01T00:00:00Z,,,\n"),
+            Format::Csv
+        ));
+    }
+
+    #[test]
+    fn rejects_inconsistent_saldo_and_limite() {
+        assert!(refused(
+            r#"[{"id": 1, "limite": 100, "saldo": -101}]"#,
+            Format::Json
+        ));
+        assert!(refused(
+            r#"[{"id": 1, "limite": -1, "saldo": 0}]"#,
+            Format::Json
+        ));
+        assert!(refused(
+            r#"[{"id": 1, "limite": 100, "saldo": 0, "limite_inicial": -1}]"#,
+            Format::Json
+        ));
+        // The limite must be the last novo_limite, or limite_inicial without one.
+        assert!(refused(
+            r#"[{"id": 1, "limite": 100, "saldo": 0, "limite_inicial": 50}]"#,
+            Format::Json
+        ));
+        assert!(refused(
+            r#"[{"id": 1, "limite": 100, "saldo": 0, "transacoes": [
+                {"valor": 0, "tipo": "c", "descricao": "", "realizada_em": "2024-01-01T00:00:00Z", "novo_limite": 200}
+            ]}]"#,
+            Format::Json
+        ));
+        // A reversal points to an earlier transaction, reversed only once.
+        assert!(refused(
+            r#"[{"id": 1, "limite": 100, "saldo": 0, "transacoes": [
+                {"valor": 5, "tipo": "c", "descricao": "", "realizada_em": "2024-01-01T00:00:00Z"},
+                {"valor": 5, "tipo": "d", "descricao": "", "realizada_em": "2024-01-01T00:00:00Z", "estorno_de": 1},
+                {"valor": 5, "tipo": "d", "descricao": "", "realizada_em": "2024-01-01T00:00:00Z", "estorno_de": 1}
+            ]}]"#,
+            Format::Json
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_reservas() {
+        // Past ultima_reserva, repeated, not positive, over the limite.
+        assert!(refused(
+            r#"[{"id": 1, "limite": 100, "saldo": 0, "ultima_reserva": 1, "reservas": [{"reserva": 2, "valor": 5}]}]"#,
+            Format::Json
+        ));
+        assert!(refused(
+            r#"[{"id": 1, "limite": 100, "saldo": 0, "ultima_reserva": 2, "reservas": [{"reserva": 2, "valor": 5}, {"reserva": 2, "valor": 5}]}]"#,
+            Format::Json
+        ));
+        assert!(refused(
+            r#"[{"id": 1, "limite": 100, "saldo": 0, "ultima_reserva": 2, "reservas": [{"reserva": 2, "valor": 0}]}]"#,
+            Format::Json
+        ));
+        assert!(refused(
+            r#"[{"id": 1, "limite": 100, "saldo": 0, "ultima_reserva": 2, "reservas": [{"reserva": 1, "valor": 60}, {"reserva": 2, "valor": 41}]}]"#,
+            Format::Json
+        ));
+        assert!(refused(
+            &format!("{CSV_HEADER}\n1,100,0,,2,2-5,,,,,,,\n"),
+            Format::Csv
+        ));
+    }
+
+    #[test]
+    fn rejects_duplicate_ids() {
+        assert!(refused(
+            r#"[{"id": 1, "limite": 100, "saldo": 0}, {"id": 1, "limite": 100, "saldo": 0}]"#,
+            Format::Json
+        ));
+        assert!(refused(
+            &format!(
+                "{CSV_HEADER}\n1,100,0,,0,,,,,,,,,\n2,100,0,,0,,,,,,,,,\n1,100,0,,0,,,,,,,,,\n"
+            ),
+            Format::Csv
+        ));
+        assert!(refused(
+            r#"[{"id": 0, "limite": 100, "saldo": 0}]"#,
+            Format::Json
+        ));
+
+        assert!(refused(
+            r#"[{"id": 1, "limite": 100, "saldo": -5, "transacoes": [
+                {"valor": 5, "tipo": "d", "descricao": "", "realizada_em": "2024-01-01T00:00:00Z", "transferencia": 2}
+            ]}]"#,
+            Format::Json
+        ));
+
+        // Nor can a dump bring back a client the DB already has.
+        let (pager, mut clients) = load(DUMP, Format::Json);
+        let dump = read_dump(r#"[{"id": 3, "limite": 100, "saldo": 0}]"#, Format::Json);
+        assert!(import(&pager, &mut clients, dump.unwrap()).is_err());
+        assert_eq!(clients[&3].saldo, -50);
+    }
+}
@@ -0,0 +1,135 @@
+use crate::client::{Client, ClientRaw};
+use crate::ledger::Ledger;
+use crate::lock_data_dir;
+use crate::pager::{DirectoryEntry, Pager, DATA_FILE};
+use crate::storage::StorageKind;
+use crate::wal::{self, WAL_FILE};
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::time::SystemTime;
+
+#[derive(Default)]
+struct Report {
+    clients: usize,
+    problems: usize,
+    repaired: usize,
+}
+
+// Checks every client and the WAL without serving requests. With `repair` the problems
+// of the client slots that have a safe fix are written back. The ledger is the history
+// and the WAL is still to be replayed, their problems are only reported.
+// Returns whether the database is clean, or was made clean, afterwards.
+pub fn fsck(data_dir: &Path, storage: StorageKind, repair: bool) -> io::Result<bool> {
+    let _lock = lock_data_dir(data_dir)?;
+    let data_path = data_dir.join(DATA_FILE);
+    if !data_path.exists() {
+        eprintln!("fsck: {} does not exist", data_path.display());
+        return Err(io::Error::new(ErrorKind::NotFound, "no database to check"));
+    }
+    let pager = Pager::open(&data_path, storage).map_err(|e| {
+        eprintln!("fsck: Could not open {}: {e}", data_path.display());
+        io::Error::new(ErrorKind::InvalidData, e.to_string())
+    })?;
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64;
+
+    let mut report = Report::default();
+    for entry in pager.entries() {
+        report.clients += 1;
+        check_client(&pager, &entry, now, repair, &mut report)?;
+    }
+    if repair && report.repaired > 0 {
+        pager.sync()?;
+    }
+    for problem in wal::check(&data_dir.join(WAL_FILE))? {
+        println!("fsck: {WAL_FILE}: {problem}");
+        report.problems += 1;
+    }
+
+    println!(
+        "fsck: {} clients checked, {} problems found, {} repaired",
+        report.clients, report.problems, report.repaired
+    );
+    Ok(report.problems == report.repaired)
+}
+
+fn check_client(
+    pager: &Pager,
+    entry: &DirectoryEntry,
+    now: u64,
+    repair: bool,
+    report: &mut Report,
+) -> io::Result<()> {
+    let id = entry.id;
+    let mut newest: Option<(u64, u32, ClientRaw)> = None;
+    let mut bad_slots = 0;
+    for page in entry.slots {
+        match Client::read_slot(pager, page) {
+            Ok((version, client)) => {
+                if newest.is_none() || newest.unwrap().0 < version {
+                    newest = Some((version, page, client));
+                }
+            }
+            // A client saved only once never wrote its second slot.
+            Err(_) if pager.read_page(page)?.iter().all(|byte| *byte == 0) => {}
+            Err(e) => {
+                println!("fsck: client {id}: slot page {page} is unreadable: {e}");
+                bad_slots += 1;
+            }
+        }
+    }
+    report.problems += bad_slots;
+    if newest.is_none() {
+        println!("fsck: client {id}: no intact slot, cannot be repaired");
+        return Ok(());
+    }
+    let (version, page, mut client) = newest.unwrap();
+    if client.id() != id {
+        println!(
+            "fsck: client {id}: slot holds client {}, cannot be repaired",
+            client.id()
+        );
+        report.problems += 1;
+        return Ok(());
+    }
+
+    let problems = client.check(now);
+    for problem in problems.iter() {
+        println!("fsck: client {id}: {problem}");
+    }
+    report.problems += problems.len();
+    // The fixed state goes to the other slot, so a bad slot is rewritten as well.
+    if repair && (bad_slots > 0 || !problems.is_empty()) {
+        client.repair(now);
+        let fixed = bad_slots + problems.len() - client.check(now).len();
+        let other = if entry.slots[0] == page {
+            entry.slots[1]
+        } else {
+            entry.slots[0]
+        };
+        Client::write_slot(pager, other, version + 1, &client)?;
+        report.repaired += fixed;
+    }
+
+    let ledger = Ledger::open(pager, entry.ledger);
+    if let Err(e) = ledger {
+        println!("fsck: client {id}: ledger is unreadable: {e}");
+        report.problems += 1;
+        return Ok(());
+    }
+    let ledger = ledger.unwrap();
+    for problem in Ledger::check_chain(pager, entry.ledger)? {
+        println!("fsck: client {id}: ledger: {problem}");
+        report.problems += 1;
+    }
+    // Rewriting a record would change the history, an operator has to look at it.
+    for (seq, problems) in ledger.check(pager, now)? {
+        for problem in problems.iter() {
+            println!("fsck: client {id}: ledger record {seq}: {problem}");
+        }
+        report.problems += problems.len();
+    }
+    Ok(())
+}
@@ -0,0 +1,284 @@
+use crate::client::{LoadError, TransacaoRaw};
+use crate::crc::crc32;
+use crate::pager::{Pager, PAGE_SIZE, SIZE_PAGE_LINK};
+use database::Transacao;
+use std::collections::HashSet;
+use std::io::{self, ErrorKind};
+use std::mem::size_of;
+
+// How a record relates to others, all zeros for a plain transaction.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[repr(C, packed)]
+pub struct Link {
+    // `seq` of the record this one reverses.
+    pub estorno: u64,
+    // Id of the other client of a transfer.
+    pub transferencia: u32,
+    // Set on a change of the limite, which moves no money. `limite` is the new one.
+    pub update_limit: bool,
+    pub limite: i64,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct LedgerRecordRaw {
+    crc: u32,
+    lsn: u64,
+    transacao: TransacaoRaw,
+    link: Link,
+}
+const SIZE_LEDGER_RECORD: usize = size_of::<LedgerRecordRaw>();
+const RECORDS_PER_PAGE: usize = (PAGE_SIZE - SIZE_PAGE_LINK) / SIZE_LEDGER_RECORD;
+
+impl LedgerRecordRaw {
+    fn checksum(&self) -> u32 {
+        let buff: &[u8; SIZE_LEDGER_RECORD] = unsafe { std::mem::transmute(self) };
+        crc32(&buff[size_of::<u32>()..])
+    }
+}
+
+// Checked on the bytes, `Link` has a `bool` that only an intact record is sure to hold.
+fn intact(buff: &[u8]) -> bool {
+    let crc = u32::from_ne_bytes(buff[..size_of::<u32>()].try_into().unwrap());
+    crc == crc32(&buff[size_of::<u32>()..SIZE_LEDGER_RECORD])
+}
+
+fn raw_record(buff: &[u8], seq: u64) -> io::Result<LedgerRecordRaw> {
+    if !intact(buff) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("corrupted ledger record {seq}"),
+        ));
+    }
+    Ok(unsafe { std::ptr::read(buff.as_ptr() as *const _) })
+}
+
+fn decode(raw: LedgerRecordRaw, seq: u64) -> io::Result<(Transacao, Link)> {
+    let transacao = Transacao::try_from(&{ raw.transacao })
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("ledger record {seq}: {e}")))?;
+    Ok((transacao, raw.link))
+}
+
+// Append-only history of every transaction of one client, kept in a chain of pages.
+// Records are only made durable at checkpoints; until then the WAL covers them,
+// and `last_lsn` makes the WAL replay skip what is already here.
+#[derive(Debug)]
+pub struct Ledger {
+    pages: Vec<u32>,
+    len: u64,
+    last_lsn: u64,
+    // `seq` of every record that was reversed.
+    reversed: HashSet<u64>,
+}
+
+impl Ledger {
+    pub fn new(first: u32) -> Ledger {
+        Ledger {
+            pages: vec![first],
+            len: 0,
+            last_lsn: 0,
+            reversed: HashSet::new(),
+        }
+    }
+
+    pub fn open(pager: &Pager, first: u32) -> Result<Ledger, LoadError> {
+        let mut ledger = Ledger::new(first);
+        let mut page = first;
+        loop {
+            let buff = pager.read_page(page).map_err(LoadError::Io)?;
+            let mut nrecords = 0;
+            for chunk in buff[SIZE_PAGE_LINK..]
+                .chunks_exact(SIZE_LEDGER_RECORD)
+                .take(RECORDS_PER_PAGE)
+            {
+                if !intact(chunk) {
+                    break;
+                }
+                let raw: LedgerRecordRaw = unsafe { std::ptr::read(chunk.as_ptr() as *const _) };
+                nrecords += 1;
+                ledger.last_lsn = raw.lsn;
+                if raw.link.estorno != 0 {
+                    ledger.reversed.insert(raw.link.estorno);
+                }
+            }
+            ledger.len += nrecords as u64;
+
+            let next = u32::from_ne_bytes(buff[..SIZE_PAGE_LINK].try_into().unwrap());
+            if next == 0 || nrecords < RECORDS_PER_PAGE {
+                break;
+            }
+            page = next;
+            ledger.pages.push(page);
+        }
+        Ok(ledger)
+    }
+
+    // Records with an LSN already in the ledger are skipped, so replaying the WAL is idempotent.
+    pub fn append(
+        &mut self,
+        pager: &Pager,
+        lsn: u64,
+        transacao: &Transacao,
+        link: Link,
+    ) -> io::Result<()> {
+        if lsn <= self.last_lsn {
+            return Ok(());
+        }
+        let page_indx = self.len as usize / RECORDS_PER_PAGE;
+        if page_indx == self.pages.len() {
+            let page = pager.allocate()?;
+            pager.write_at(*self.pages.last().unwrap(), 0, &page.to_ne_bytes())?;
+            self.pages.push(page);
+        }
+        let mut raw = LedgerRecordRaw {
+            crc: 0,
+            lsn,
+            transacao: transacao.into(),
+            link,
+        };
+        raw.crc = raw.checksum();
+        let buff: &[u8; SIZE_LEDGER_RECORD] = unsafe { std::mem::transmute(&raw) };
+        let offset = SIZE_PAGE_LINK + (self.len as usize % RECORDS_PER_PAGE) * SIZE_LEDGER_RECORD;
+        pager.write_at(self.pages[page_indx], offset, buff)?;
+        self.len += 1;
+        self.last_lsn = lsn;
+        if link.estorno != 0 {
+            self.reversed.insert(link.estorno);
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_reversed(&self, seq: u64) -> bool {
+        self.reversed.contains(&seq)
+    }
+
+    fn read_raw(&self, pager: &Pager, seq: u64) -> io::Result<LedgerRecordRaw> {
+        let indx = (seq - 1) as usize;
+        let mut buff = [0; SIZE_LEDGER_RECORD];
+        let offset = SIZE_PAGE_LINK + (indx % RECORDS_PER_PAGE) * SIZE_LEDGER_RECORD;
+        pager.read_at(self.pages[indx / RECORDS_PER_PAGE], offset, &mut buff)?;
+        raw_record(&buff, seq)
+    }
+
+    pub fn read(&self, pager: &Pager, seq: u64) -> io::Result<(Transacao, Link)> {
+        decode(self.read_raw(pager, seq)?, seq)
+    }
+
+    // Every record, newest first, reading each page of the chain once. Stops as soon as
+    // `f` returns false.
+    pub fn scan_rev(
+        &self,
+        pager: &Pager,
+        mut f: impl FnMut(u64, Transacao, Link) -> bool,
+    ) -> io::Result<()> {
+        let mut last = self.len;
+        while last > 0 {
+            let page_indx = (last - 1) as usize / RECORDS_PER_PAGE;
+            let page = pager.read_page(self.pages[page_indx])?;
+            let first = (page_indx * RECORDS_PER_PAGE) as u64 + 1;
+            for seq in (first..=last).rev() {
+                let offset = SIZE_PAGE_LINK + ((seq - first) as usize) * SIZE_LEDGER_RECORD;
+                let raw = raw_record(&page[offset..offset + SIZE_LEDGER_RECORD], seq)?;
+                let (transacao, link) = decode(raw, seq)?;
+                if !f(seq, transacao, link) {
+                    return Ok(());
+                }
+            }
+            last = first - 1;
+        }
+        Ok(())
+    }
+
+    // `open` takes the first record that fails its CRC as the end of the ledger. Finds what
+    // it would hide: a damaged record, which is not blank like the slots never written,
+    // or records and pages after the end.
+    pub fn check_chain(pager: &Pager, first: u32) -> io::Result<Vec<String>> {
+        let mut problems = Vec::new();
+        let mut visited = HashSet::new();
+        let mut page = first;
+        let mut len = 0;
+        let mut ended = false;
+        loop {
+            if !visited.insert(page) {
+                problems.push(format!("page {page} links back into the chain"));
+                return Ok(problems);
+            }
+            let buff = pager.read_page(page)?;
+            for chunk in buff[SIZE_PAGE_LINK..]
+                .chunks_exact(SIZE_LEDGER_RECORD)
+                .take(RECORDS_PER_PAGE)
+            {
+                let blank = chunk.iter().all(|byte| *byte == 0);
+                if ended && !blank {
+                    problems.push(format!(
+                        "page {page} has records after the end of the ledger at record {len}"
+                    ));
+                    return Ok(problems);
+                }
+                if ended {
+                    continue;
+                }
+                if intact(chunk) {
+                    len += 1;
+                    continue;
+                }
+                if !blank {
+                    problems.push(format!(
+                        "record {} is corrupted, it and every later record are lost",
+                        len + 1
+                    ));
+                    return Ok(problems);
+                }
+                ended = true;
+            }
+            let next = u32::from_ne_bytes(buff[..SIZE_PAGE_LINK].try_into().unwrap());
+            if next == 0 {
+                return Ok(problems);
+            }
+            if ended {
+                problems.push(format!(
+                    "page {page} links to page {next} after the end of the ledger at record {len}"
+                ));
+                return Ok(problems);
+            }
+            page = next;
+        }
+    }
+
+    // Records that fail `TransacaoRaw::check`, with their problems.
+    pub fn check(&self, pager: &Pager, now: u64) -> io::Result<Vec<(u64, Vec<String>)>> {
+        let mut out = Vec::new();
+        for seq in 1..=self.len {
+            let problems = self.read_raw(pager, seq)?.transacao.check(now);
+            if !problems.is_empty() {
+                out.push((seq, problems));
+            }
+        }
+        Ok(out)
+    }
+
+    // Up to `limit` records with `seq < before` (all of them when `before == 0`), newest first.
+    pub fn read_before(
+        &self,
+        pager: &Pager,
+        before: u64,
+        limit: usize,
+    ) -> io::Result<Vec<(u64, Transacao, Link)>> {
+        let end = if before == 0 {
+            self.len + 1
+        } else {
+            before.min(self.len + 1)
+        };
+        let start = end.saturating_sub(limit as u64).max(1);
+        let mut out = Vec::with_capacity(end.saturating_sub(start) as usize);
+        for seq in (start..end).rev() {
+            let (transacao, link) = self.read(pager, seq)?;
+            out.push((seq, transacao, link));
+        }
+        Ok(out)
+    }
+}
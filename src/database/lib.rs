@@ -1,24 +1,53 @@
-use std::marker::PhantomData;
-use std::mem::{self, size_of};
+use std::io;
 use std::net::{SocketAddr, UdpSocket};
-use std::ptr::{read, write};
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
 use serde::{Deserialize, Serialize};
 
-pub const SIZE_OPERATION: usize = size_of::<Operation>();
-pub const SIZE_EXTRATO: usize = size_of::<Extrato>();
-pub const SIZE_TRANSACAO_RETURN: usize = size_of::<TransacaoReturn>();
+pub mod codec;
+use codec::{open_envelope, Encode};
+
+// Every message between the backends and the DB goes through `codec`.
+pub const SIZE_OPERATION: usize = Operation::SIZE;
+pub const SIZE_EXTRATO: usize = Extrato::SIZE;
+pub const SIZE_TRANSACAO_RETURN: usize = TransacaoReturn::SIZE;
+pub const SIZE_CLIENTE_RETURN: usize = ClienteReturn::SIZE;
+pub const SIZE_HOLD_RETURN: usize = HoldReturn::SIZE;
+pub const SIZE_SALDO_RETURN: usize = SaldoReturn::SIZE;
+pub const SIZE_REJEICAO: usize = Rejeicao::SIZE;
+pub const SIZE_PAGINA_HEADER: usize = PaginaHeader::SIZE;
+pub const SIZE_TRANSACAO_LEDGER: usize = TransacaoLedger::SIZE;
 pub const NCHAR_DESCRIPTION: usize = 10; // 10 chars + \0
 pub const NTRANSACOES: usize = 10; // 10 chars + \0
+pub const MAX_PAGINA: usize = 100;
+pub const SIZE_IDEMPOTENCY_KEY: usize = 64;
+pub const SIZE_PAGINA_MAX: usize = SIZE_PAGINA_HEADER + MAX_PAGINA * SIZE_TRANSACAO_LEDGER;
+pub const SIZE_REJEICOES_MAX: usize = SIZE_PAGINA_HEADER + MAX_PAGINA * SIZE_REJEICAO;
 
-pub type BufferOperation = [u8; SIZE_OPERATION];
 pub type BufferDescription = [char; NCHAR_DESCRIPTION];
-pub type BufferExtrato = [u8; SIZE_EXTRATO];
-pub type BufferTranscaoReturn = [u8; SIZE_TRANSACAO_RETURN];
+// Zero padded, all zeros means the request has no key.
+pub type BufferIdempotencyKey = [u8; SIZE_IDEMPOTENCY_KEY];
 pub const PORT_DB: u16 = 7000;
 
-pub const RES_ERROR: [u8; 1] = [1];
+// First byte of every reply of the DB, the payload only follows `Status::Ok`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Status {
+    Ok,
+    NotFound,
+    // The saldo, the limite or the holds of the client do not allow it.
+    LimitExceeded,
+    // The operation cannot be decoded or makes no sense, retrying does not help.
+    Malformed,
+    StorageError,
+    // The DB cannot take it now, the same operation may succeed later.
+    Busy,
+    // Valid, but the state it applies to does not allow it, like reversing a
+    // transaction twice, opening a hold when every slot is taken or creating a client
+    // once every id is taken.
+    Conflict,
+}
 
 #[derive(Clone, Copy, Debug)]
 #[repr(C, packed)]
@@ -41,42 +70,169 @@ pub struct TransacaoExtrato {
 pub struct Extrato {
     pub total: i64,
     pub limite: i64,
+    pub disponivel: i64,
     pub transacoes: [TransacaoExtrato; NTRANSACOES],
+    // Of a filtered `Extrato`, more transactions pass the filter than the ones listed.
+    pub mais: bool,
 }
 
+// `saldo` is the booked balance, `disponivel` what is left of it once the open holds are taken out.
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 #[repr(C, packed)]
 pub struct TransacaoReturn {
     pub limite: i64,
     pub saldo: i64,
+    pub disponivel: i64,
+}
+
+// Reply to `OperationKind::Hold`, `reserva` names the hold for the capture or the release.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[repr(C, packed)]
+pub struct HoldReturn {
+    pub reserva: u64,
+    pub limite: i64,
+    pub saldo: i64,
+    pub disponivel: i64,
+}
+
+// Reply to `OperationKind::Saldo`, the booked saldo and the limite in force at `Operation::em`.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[repr(C, packed)]
+pub struct SaldoReturn {
+    pub saldo: i64,
+    pub limite: i64,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[repr(C, packed)]
+pub struct ClienteReturn {
+    pub id: u32,
+    pub limite: i64,
+    pub saldo: i64,
+}
+
+// Payload of `OperationKind::CreateClient`, the DB picks the id of the new client.
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct NovoCliente {
+    pub limite: i64,
+    pub saldo: i64,
+}
+
+// A transaction read back from the ledger, `seq` is its position (from 1) in the history.
+// `estorno` is the `seq` of the transaction it reverses and `transferencia` the other
+// client of a transfer, both 0 for a regular one. With `update_limit` it is a change
+// of the limite to `limite`, which moves no money.
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct TransacaoLedger {
+    pub seq: u64,
+    pub value: i64,
+    pub transacao_description: BufferDescription,
+    pub timestap: SystemTime,
+    pub estorno: u64,
+    pub transferencia: u32,
+    pub update_limit: bool,
+    pub limite: i64,
+}
+
+// Why a transaction, a transfer, a reversal, a hold or a capture was refused.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u32)]
+pub enum Motivo {
+    // The saldo would go under the limite.
+    Limite,
+    // The saldo fits the limite, but not once the open holds are taken out.
+    Reservas,
+}
+
+// A refused transaction read back from the audit log, `seq` is its position (from 1)
+// among the refusals of the client. `transferencia` is the payee of a refused transfer,
+// 0 for a regular transaction.
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct Rejeicao {
+    pub seq: u64,
+    pub value: i64,
+    pub transacao_description: BufferDescription,
+    pub timestap: SystemTime,
+    pub motivo: Motivo,
+    pub transferencia: u32,
 }
 
+// Reply to `OperationKind::Transacoes`, followed by `ntransacoes` `TransacaoLedger`, newest first.
+// `Rejeicoes` gets the same header, followed by `Rejeicao`.
+// `next_before` is the cursor for the next page, 0 when there is none.
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct PaginaHeader {
+    pub ntransacoes: u32,
+    pub next_before: u64,
+}
+
+// Cursor of `OperationKind::Transacoes` and `Rejeicoes`: up to `limit` transactions with `seq < before`.
+// `before == 0` starts from the newest one.
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct Pagina {
+    pub limit: u32,
+    pub before: u64,
+}
+
+// Of `OperationKind::Extrato`. With `ativo` the whole ledger is searched, newest first,
+// for the transactions with a timestamp in `[de, ate]`, in nanoseconds since the epoch,
+// and of `tipo`: `b'c'`, `b'd'` or 0 for both.
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct Filtro {
+    pub ativo: bool,
+    pub tipo: u8,
+    pub de: u64,
+    pub ate: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
 #[repr(C)]
 pub enum OperationKind {
     Extrato,
     Transacao,
+    Transacoes,
+    CreateClient,
+    Estorno,
+    Transferencia,
+    UpdateLimit,
+    Hold,
+    Capture,
+    Release,
+    Rejeicoes,
+    Saldo,
 }
 
+#[derive(Clone, Copy)]
 #[repr(C, packed)]
 pub struct Operation {
+    // Echoed back in the reply, set by `DBconn::request`. 0 means the sender does not
+    // retransmit, the DB then does not remember its reply.
+    pub request_id: u64,
     pub kind: OperationKind,
-    pub id: u8,
+    pub id: u32,
     pub transacao: Transacao,
-}
-
-pub struct Converter<T, B: Copy>(PhantomData<T>, PhantomData<B>);
-impl<T, B: Copy> Converter<T, B> {
-    const _SIZE_OK_: () = assert!(size_of::<T>() == size_of::<B>());
-    pub fn from_buffer(buffer: &B) -> T {
-        unsafe { read(buffer as *const _ as *const T) }
-    }
-    pub fn to_buffer(op: &T) -> B {
-        unsafe {
-            let mut buffer: B = mem::zeroed();
-            write(&mut buffer, *(op as *const _ as *const B));
-            buffer
-        }
-    }
+    pub pagina: Pagina,
+    pub filtro: Filtro,
+    // Of `OperationKind::Saldo`, the instant asked for, in nanoseconds since the epoch.
+    pub em: u64,
+    pub novo_cliente: NovoCliente,
+    // Of `OperationKind::Estorno`, the `seq` in the ledger of the transaction to reverse.
+    pub estorno: u64,
+    // Of `OperationKind::Transferencia`, the client `id` pays `transacao.value` to.
+    pub para: u32,
+    // Of `OperationKind::UpdateLimit`, the new limite of client `id`.
+    pub limite: i64,
+    // Of `OperationKind::Capture` and `Release`, the hold of client `id`. A hold reserves
+    // `transacao.value` and a capture debits `transacao` in its place.
+    pub reserva: u64,
+    // Of `OperationKind::Transacao` and `Transferencia`, a repeated key gets the reply of the first request.
+    pub idempotency_key: BufferIdempotencyKey,
 }
 
 pub struct DBconn {
@@ -84,19 +240,96 @@ pub struct DBconn {
 }
 
 impl DBconn {
-    pub fn new() -> Result<DBconn, ()> {
+    pub fn new() -> std::io::Result<DBconn> {
         for port in (PORT_DB + 1)..(PORT_DB + 1000) {
             let dbconn = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], port)));
             if let Ok(dbconn) = dbconn {
-                if let Err(_) = dbconn.connect(SocketAddr::from(([127, 0, 0, 1], PORT_DB))) {
+                if dbconn
+                    .connect(SocketAddr::from(([127, 0, 0, 1], PORT_DB)))
+                    .is_err()
+                {
                     continue;
                 }
                 return Ok(DBconn { dbconn });
             }
         }
-        Err(())
+        Err(std::io::Error::new(
+            std::io::ErrorKind::AddrInUse,
+            "no free port to connect to the DB",
+        ))
     }
     pub fn dbconn(&self) -> &UdpSocket {
         &self.dbconn
     }
+
+    // Sends `op` until a reply to it arrives, copying its payload into `payload`. Replies
+    // to earlier requests, late or duplicated, are dropped. The DB answers a
+    // retransmitted operation with the reply it already sent, it is never applied twice.
+    pub fn request(&self, op: &Operation, payload: &mut [u8]) -> io::Result<(Status, usize)> {
+        let request_id = next_request_id();
+        let request = Operation { request_id, ..*op }.to_bytes();
+        // The request id and the status come before the payload.
+        let mut reply = vec![0; SIZE_REPLY_HEADER + payload.len()];
+        for _ in 0..MAX_RETRANSMISSIONS + 1 {
+            let nbytes = self.dbconn.send(&request)?;
+            if nbytes != request.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    format!("sent {} of {} bytes", nbytes, request.len()),
+                ));
+            }
+            let deadline = Instant::now() + RETRANSMIT_AFTER;
+            while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+                if timeout.is_zero() {
+                    break;
+                }
+                self.dbconn.set_read_timeout(Some(timeout))?;
+                let nbytes = match self.dbconn.recv(&mut reply) {
+                    Ok(nbytes) => nbytes,
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) =>
+                    {
+                        break
+                    }
+                    Err(e) => return Err(e),
+                };
+                // A stray or corrupted datagram is not the reply, which may still come.
+                let envelope = open_envelope(&reply[..nbytes]);
+                if let Err(e) = envelope {
+                    eprintln!("DBconn: Dropping undecodable reply: {e}");
+                    continue;
+                }
+                let (reply_id, status, body) = envelope.unwrap();
+                if reply_id != request_id {
+                    eprintln!("DBconn: Dropping reply to request {}", reply_id);
+                    continue;
+                }
+                payload[..body.len()].copy_from_slice(body);
+                return Ok((status, body.len()));
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no reply to request {}", request_id),
+        ))
+    }
+}
+
+// Time waited for a reply before sending the operation again.
+pub const RETRANSMIT_AFTER: Duration = Duration::from_millis(200);
+pub const MAX_RETRANSMISSIONS: usize = 9;
+pub const SIZE_REPLY_HEADER: usize = 8 + 1;
+
+static REQUEST_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+// Unique among the processes talking to the DB: the pid goes in the high half. A
+// `DBconn` reuses the ports of earlier ones, so ids cannot restart with each one.
+fn next_request_id() -> u64 {
+    let count = REQUEST_COUNTER
+        .fetch_add(1, Ordering::Relaxed)
+        .wrapping_add(1);
+    ((std::process::id() as u64) << 32) | count as u64
 }
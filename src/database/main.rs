@@ -1,11 +1,42 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::Write;
 use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::Arc;
+use std::thread;
 
+mod audit;
 mod client;
+mod config;
+mod crc;
+mod dump;
+mod fsck;
+mod ledger;
+mod pager;
+mod replication;
+mod seed;
+mod storage;
+mod wal;
+mod worker;
+use audit::{AuditLog, AUDIT_FILE};
 use client::Client;
-use database::{
-    BufferExtrato, BufferOperation, BufferTranscaoReturn, Converter, Operation, OperationKind,
-    TransacaoReturn, PORT_DB, RES_ERROR, SIZE_OPERATION,
-};
+use config::{Command, Config};
+use database::codec::{envelope, request_id_of, Decode};
+use database::{Operation, OperationKind, Status, Transacao, SIZE_IDEMPOTENCY_KEY};
+use ledger::Link;
+use pager::{Pager, DATA_FILE};
+use replication::{Follower, Primary, HEARTBEAT_INTERVAL, SIZE_MESSAGE};
+use storage::StorageKind;
+use wal::{Record, RecordKind, Wal, WAL_FILE};
+use worker::{Job, Log, Seen, Shared, QUEUE_MAX};
+
+const LOCK_FILE: &str = "database.lock";
+// Copy of `DATA_FILE` an import writes to, it replaces the original once complete.
+const IMPORT_FILE: &str = "database.db.import";
 
 fn send_buffer(socket: &UdpSocket, buffer: &[u8], addr: &SocketAddr) {
     if let Err(e) = socket.send_to(buffer, addr) {
@@ -13,63 +44,574 @@ fn send_buffer(socket: &UdpSocket, buffer: &[u8], addr: &SocketAddr) {
     }
 }
 
+// The lock is released by the OS when the process exits, even after a crash.
+fn lock_data_dir(data_dir: &Path) -> std::io::Result<File> {
+    std::fs::create_dir_all(data_dir)?;
+    let path = data_dir.join(LOCK_FILE);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let owner = std::fs::read_to_string(&path).unwrap_or_default();
+            eprintln!(
+                "DB: {} is locked by another database process (pid {}), refusing to start",
+                data_dir.display(),
+                owner.trim()
+            );
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::WouldBlock,
+                "data directory already in use",
+            ));
+        }
+        Err(TryLockError::Error(e)) => return Err(e),
+    }
+    file.set_len(0)?;
+    write!(file, "{}", std::process::id())?;
+    Ok(file)
+}
+
+// A damaged client must not be silently replaced, so any load error stops the DB.
+fn load_clients(pager: &Pager) -> std::io::Result<HashMap<u32, Client>> {
+    let mut clients = HashMap::new();
+    for entry in pager.entries() {
+        let id = entry.id;
+        let client = Client::load_client(pager, &entry).map_err(|e| {
+            eprintln!("DB: Could not load client {id}: {e}");
+            std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+        })?;
+        clients.insert(id, client);
+    }
+    Ok(clients)
+}
+
+fn checkpoint(
+    pager: &Pager,
+    clients: &mut HashMap<u32, Client>,
+    wal: &mut Wal,
+) -> std::io::Result<()> {
+    for client in clients.values_mut() {
+        client.checkpoint(pager)?;
+    }
+    pager.sync()?;
+    wal.truncate()
+}
+
 fn main() -> std::io::Result<()> {
-    let clients = &mut [
-        Client::load_client(1).unwrap_or_else(|| Client::new(1, 100_000, 0)),
-        Client::load_client(2).unwrap_or_else(|| Client::new(2, 80_000, 0)),
-        Client::load_client(3).unwrap_or_else(|| Client::new(3, 1_000_000, 0)),
-        Client::load_client(4).unwrap_or_else(|| Client::new(4, 10_000_000, 0)),
-        Client::load_client(5).unwrap_or_else(|| Client::new(5, 500_000, 0)),
-    ];
-    for client in clients.iter() {
+    let config = Config::from_args();
+    match config.command {
+        Command::Serve => serve(&config),
+        Command::Export => export(&config),
+        Command::Import => import(&config),
+        Command::Fsck { repair } => {
+            if !fsck::fsck(&config.data_dir, config.storage, repair)? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+    }
+}
+
+// Loads every client and replays the WAL, leaving it empty. The memory storage starts
+// empty and leaves `DATA_FILE` and `WAL_FILE` alone, they may belong to another run.
+fn open_database(
+    data_dir: &Path,
+    storage: StorageKind,
+) -> std::io::Result<(Pager, HashMap<u32, Client>, Wal)> {
+    let data_path = data_dir.join(DATA_FILE);
+    let pager = Pager::open(&data_path, storage).map_err(|e| {
+        eprintln!("DB: Could not open {}: {e}", data_path.display());
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+    });
+    let pager = pager?;
+    let mut clients = load_clients(&pager)?;
+
+    let last_lsn = clients.values().map(|client| client.lsn).max().unwrap_or(0);
+    if storage == StorageKind::Memory {
+        return Ok((pager, clients, Wal::memory(last_lsn)));
+    }
+    let (mut wal, records) = Wal::open(&data_dir.join(WAL_FILE), last_lsn)?;
+    for record in records {
+        apply_record(&pager, &mut [&mut clients], record.lsn, &record.record)?;
+    }
+    checkpoint(&pager, &mut clients, &mut wal)?;
+    Ok((pager, clients, wal))
+}
+
+// Applies a record that is already in the WAL. Records older than the state of their
+// client are skipped, so the same record can be applied again after a crash.
+// `clients` must include every map holding a client of the record, new clients go to the first.
+fn apply_record(
+    pager: &Pager,
+    clients: &mut [&mut HashMap<u32, Client>],
+    lsn: u64,
+    record: &Record,
+) -> std::io::Result<()> {
+    let id = record.id;
+    match record.kind {
+        RecordKind::Transacao => {
+            let link = Link {
+                estorno: record.estorno,
+                ..Link::default()
+            };
+            apply_side(pager, clients, lsn, record, id, record.transacao, link)?;
+        }
+        RecordKind::Transferencia => {
+            let debit = record.transacao;
+            let credit = Transacao {
+                value: -debit.value,
+                ..debit
+            };
+            let link = Link {
+                transferencia: record.para,
+                ..Link::default()
+            };
+            apply_side(pager, clients, lsn, record, id, debit, link)?;
+            let link = Link {
+                transferencia: id,
+                ..Link::default()
+            };
+            apply_side(pager, clients, lsn, record, record.para, credit, link)?;
+        }
+        RecordKind::UpdateLimit => {
+            let link = Link {
+                update_limit: true,
+                limite: record.limite,
+                ..Link::default()
+            };
+            apply_side(pager, clients, lsn, record, id, record.transacao, link)?;
+        }
+        RecordKind::Hold | RecordKind::Capture | RecordKind::Release => {
+            let client = clients.iter_mut().find_map(|clients| clients.get_mut(&id));
+            if client.is_none() {
+                eprintln!("DB: WAL record {} has invalid id {}", lsn, id);
+                return Ok(());
+            }
+            let client = client.unwrap();
+            if lsn <= client.lsn {
+                return Ok(());
+            }
+            match record.kind {
+                RecordKind::Hold => client.apply_hold(lsn, record.reserva, record.transacao.value),
+                RecordKind::Capture => {
+                    client.apply_capture(pager, lsn, record.reserva, record.transacao)?
+                }
+                _ => client.apply_release(lsn, record.reserva),
+            }
+        }
+        RecordKind::CreateClient => {
+            if let Entry::Vacant(entry) = clients[0].entry(id) {
+                entry.insert(Client::new(pager, id, record.limite, record.saldo, lsn)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+// The idempotency key of the record belongs to `record.id`, the other side of a transfer has none.
+fn apply_side(
+    pager: &Pager,
+    clients: &mut [&mut HashMap<u32, Client>],
+    lsn: u64,
+    record: &Record,
+    id: u32,
+    transacao: Transacao,
+    link: Link,
+) -> std::io::Result<()> {
+    let client = clients.iter_mut().find_map(|clients| clients.get_mut(&id));
+    if client.is_none() {
+        eprintln!("DB: WAL record {} has invalid id {}", lsn, id);
+        return Ok(());
+    }
+    let client = client.unwrap();
+    if lsn <= client.lsn {
+        return Ok(());
+    }
+    let idempotency_key = if id == record.id {
+        record.idempotency_key
+    } else {
+        [0; SIZE_IDEMPOTENCY_KEY]
+    };
+    client.apply_transacao(pager, lsn, transacao, idempotency_key, link)
+}
+
+fn export(config: &Config) -> std::io::Result<()> {
+    let _lock = lock_data_dir(&config.data_dir)?;
+    let (pager, clients, _) = open_database(&config.data_dir, config.storage)?;
+    let dump = dump::export(&pager, &clients, config.format)?;
+    let path = config.file.as_ref().unwrap();
+    std::fs::write(path, dump)?;
+    println!(
+        "DB: {} clients exported to {}",
+        clients.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+fn import(config: &Config) -> std::io::Result<()> {
+    let path = config.file.as_ref().unwrap();
+    let text = std::fs::read_to_string(path);
+    if let Err(e) = text {
+        eprintln!("DB: Could not read {}: {e}", path.display());
+        return Err(e);
+    }
+    let dump = dump::read_dump(&text.unwrap(), config.format)?;
+
+    let _lock = lock_data_dir(&config.data_dir)?;
+    // Leaves the data file up to date and the WAL empty, so the copy has everything.
+    drop(open_database(&config.data_dir, config.storage)?);
+    let data_path = config.data_dir.join(DATA_FILE);
+    let import_path = config.data_dir.join(IMPORT_FILE);
+    std::fs::copy(&data_path, &import_path)?;
+    let imported = import_into(&import_path, config.storage, dump);
+    if let Err(e) = imported {
+        eprintln!(
+            "DB: Import failed, {} is left as it was",
+            data_path.display()
+        );
+        std::fs::remove_file(&import_path)?;
+        return Err(e);
+    }
+    // A crash before the rename leaves the original, after it the complete import.
+    std::fs::rename(&import_path, &data_path)?;
+    File::open(&config.data_dir)?.sync_all()
+}
+
+fn import_into(
+    path: &Path,
+    storage: StorageKind,
+    dump: Vec<(dump::DumpClient, Vec<dump::DumpEntry>)>,
+) -> std::io::Result<()> {
+    let pager = Pager::open(path, storage).map_err(|e| {
+        eprintln!("DB: Could not open {}: {e}", path.display());
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+    })?;
+    let mut clients = load_clients(&pager)?;
+    dump::import(&pager, &mut clients, dump)?;
+    for client in clients.values_mut() {
+        client.checkpoint(&pager)?;
+    }
+    pager.sync()
+}
+
+fn serve(config: &Config) -> std::io::Result<()> {
+    let seed = match &config.seed {
+        Some(path) => seed::read_seed(path)?,
+        None => Vec::new(),
+    };
+
+    let _lock = lock_data_dir(&config.data_dir)?;
+    let (pager, mut clients, mut wal) = open_database(&config.data_dir, config.storage)?;
+    seed::apply_seed(
+        &pager,
+        &mut clients,
+        &mut wal,
+        &seed,
+        config.accept_seed_drift,
+    )?;
+
+    for client in clients.values() {
         println!("{:?}", client);
     }
 
-    let socket = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], PORT_DB)))?;
+    let socket = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], config.port)))?;
+    let mut follower = config.follow.map(|primary| {
+        println!("DB: Following {} from LSN {}", primary, wal.last_lsn());
+        let mut follower = Follower::new(primary, wal.last_lsn());
+        follower.catch_up(&socket);
+        follower
+    });
+    if !config.replicas.is_empty() || config.follow.is_some() {
+        socket.set_read_timeout(Some(HEARTBEAT_INTERVAL))?;
+    }
 
+    let log = Log {
+        wal,
+        primary: Primary::new(config.replicas.clone()),
+    };
+    let audit = if config.storage == StorageKind::Memory {
+        AuditLog::memory()
+    } else {
+        AuditLog::open(&config.data_dir.join(AUDIT_FILE))?
+    };
+    let shared = Arc::new(Shared::new(pager, clients, log, audit, config.workers));
+    let mut workers = Vec::with_capacity(config.workers);
+    for indx in 0..config.workers {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_MAX);
+        let shared = shared.clone();
+        let socket = socket.try_clone()?;
+        thread::Builder::new()
+            .name(format!("worker-{indx}"))
+            .spawn(move || worker::run(shared, receiver, socket))?;
+        workers.push(sender);
+    }
+    println!("DB: Serving with {} workers", config.workers);
+
+    // Only routes: each operation goes to the worker owning its client, which
+    // keeps the operations of a client in the order they arrived.
+    let mut buf = [0; SIZE_MESSAGE];
     loop {
-        let mut buf: BufferOperation = [0; SIZE_OPERATION];
-        let (size, addr) = socket.recv_from(&mut buf)?;
-        if size != SIZE_OPERATION {
-            eprint!("DB: Invalid object recived. Size = {}", size);
-            if socket.send_to(&RES_ERROR, addr).is_err() {
-                eprint!("DB: Could not respond to {}", addr);
+        if shared.checkpoint_wanted.swap(false, Ordering::Relaxed) {
+            if let Err(e) = shared.checkpoint() {
+                eprintln!("DB: Checkpoint failed, keeping the WAL: {}", e);
             }
         }
-        let op: Operation = Converter::from_buffer(&buf);
+        if !config.replicas.is_empty() {
+            let mut log = shared.log.lock().unwrap();
+            let last_lsn = log.wal.last_lsn();
+            log.primary.heartbeat(&socket, last_lsn);
+        }
 
-        match op.kind {
-            OperationKind::Extrato => {
-                let client = clients.get(op.id as usize - 1);
-                if client.is_none() {
-                    eprint!("DB: Invalid id {}", op.id);
-                    send_buffer(&socket, &RES_ERROR, &addr);
-                    continue;
+        let received = socket.recv_from(&mut buf);
+        if let Err(e) = &received {
+            if matches!(
+                e.kind(),
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+            ) {
+                continue;
+            }
+        }
+        let (size, addr) = received?;
+        if replication::is_message(&buf[..size]) {
+            match &mut follower {
+                Some(follower) => follower.handle(&socket, &addr, &buf[..size], &shared)?,
+                None => {
+                    let shards = shared.lock_all();
+                    let clients: Vec<&Client> =
+                        shards.iter().flat_map(|clients| clients.values()).collect();
+                    let mut log = shared.log.lock().unwrap();
+                    let last_lsn = log.wal.last_lsn();
+                    log.primary
+                        .handle(&socket, &addr, &buf[..size], &clients, last_lsn);
                 }
-
-                let client = client.unwrap();
-                let buf: BufferExtrato = Converter::to_buffer(&client.extrato());
-                send_buffer(&socket, &buf, &addr);
             }
-            OperationKind::Transacao => {
-                let client = clients.get_mut(op.id as usize - 1);
-                if client.is_none() {
-                    eprint!("DB: Invalid id {}", op.id);
-                    send_buffer(&socket, &RES_ERROR, &addr);
+            continue;
+        }
+        let op = Operation::from_bytes(&buf[..size]);
+        if let Err(e) = op {
+            eprintln!("DB: Invalid operation of {} bytes: {}", size, e);
+            let request_id = request_id_of(&buf[..size]);
+            send_buffer(
+                &socket,
+                &envelope(request_id, Status::Malformed, &[]),
+                &addr,
+            );
+            continue;
+        }
+        let mut op = op.unwrap();
+        let request_id = op.request_id;
+
+        // A retransmission: the first copy is still with a worker, or already answered.
+        if request_id != 0 {
+            match shared.replies.lock().unwrap().seen(addr, request_id) {
+                Seen::New => {}
+                Seen::InFlight => continue,
+                Seen::Replied(reply) => {
+                    send_buffer(&socket, reply, &addr);
                     continue;
                 }
-                let client = client.unwrap();
-                if client.push_transacao(op.transacao).is_err() {
-                    send_buffer(&socket, &RES_ERROR, &addr);
-                    continue;
+            }
+        }
+
+        // Replicas only serve reads, every write goes through the primary.
+        if follower.is_some() && !matches!(op.kind, OperationKind::Extrato) {
+            eprintln!("DB: Refusing a write on a replica");
+            send_buffer(&socket, &envelope(request_id, Status::Busy, &[]), &addr);
+            continue;
+        }
+        if matches!(op.kind, OperationKind::Transferencia) && { op.para } == { op.id } {
+            eprintln!("DB: Refusing a transfer from client {} to itself", {
+                op.id
+            });
+            send_buffer(
+                &socket,
+                &envelope(request_id, Status::Malformed, &[]),
+                &addr,
+            );
+            continue;
+        }
+        if matches!(op.kind, OperationKind::CreateClient) {
+            let novo_cliente = op.novo_cliente;
+            if novo_cliente.limite < 0 || novo_cliente.saldo < -novo_cliente.limite {
+                eprintln!("DB: Invalid new client {:?}", novo_cliente);
+                send_buffer(
+                    &socket,
+                    &envelope(request_id, Status::Malformed, &[]),
+                    &addr,
+                );
+                continue;
+            }
+            // The id is taken here, so the worker of the new client gets the operation.
+            let id = shared
+                .max_id
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |id| id.checked_add(1));
+            // Every u32 is taken: nothing is wrong with the storage, no new client fits.
+            if id.is_err() {
+                eprintln!("DB: No id left for a new client");
+                send_buffer(&socket, &envelope(request_id, Status::Conflict, &[]), &addr);
+                continue;
+            }
+            op.id = id.unwrap() + 1;
+        }
+        let id = op.id;
+        if request_id != 0 {
+            shared.replies.lock().unwrap().start(addr, request_id);
+        }
+        match workers[shared.shard_of(id)].try_send(Job { addr, op }) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                eprintln!("DB: Worker of client {} is full, refusing", id);
+                if request_id != 0 {
+                    shared.replies.lock().unwrap().forget(addr, request_id);
                 }
-                let ret = TransacaoReturn {
-                    limite: client.limite,
-                    saldo: client.saldo,
-                };
-                let buf: BufferTranscaoReturn = Converter::to_buffer(&ret);
-                send_buffer(&socket, &buf, &addr);
+                send_buffer(&socket, &envelope(request_id, Status::Busy, &[]), &addr);
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                eprintln!("DB: Worker of client {} stopped", id);
+                std::process::exit(1);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::{BufferIdempotencyKey, NCHAR_DESCRIPTION};
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    const KEY: BufferIdempotencyKey = [7; SIZE_IDEMPOTENCY_KEY];
+
+    // Clients 1 and 2 as the last checkpoint left them, before any of the records.
+    fn checkpointed() -> (Pager, HashMap<u32, Client>) {
+        let pager = Pager::open(Path::new("replay-test"), StorageKind::Memory).unwrap();
+        let mut clients = HashMap::new();
+        for id in [1, 2] {
+            clients.insert(id, Client::new(&pager, id, 100, 0, 0).unwrap());
+        }
+        (pager, clients)
+    }
+
+    fn wal_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("replay-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn transacao(value: i64) -> Transacao {
+        Transacao {
+            value,
+            transacao_description: ['\0'; NCHAR_DESCRIPTION],
+            timestap: UNIX_EPOCH + Duration::from_secs(1),
+        }
+    }
+
+    // Runs a transfer, a hold and its capture, and crashes once the WAL is flushed:
+    // neither the ledger nor the client pages got them.
+    fn crash(path: &Path) {
+        let (_pager, mut clients) = checkpointed();
+        let (mut wal, _) = Wal::open(path, 0).unwrap();
+        let [payer, payee] = clients.get_disjoint_mut([&1, &2]);
+        let (payer, payee) = (payer.unwrap(), payee.unwrap());
+        payer
+            .push_transferencia(payee, &mut wal, transacao(30), KEY)
+            .unwrap();
+        let hold = payee.push_hold(&mut wal, 10).unwrap();
+        payee
+            .push_capture(&mut wal, hold.reserva, transacao(5))
+            .unwrap();
+        payee.push_hold(&mut wal, 20).unwrap();
+        wal.flush().unwrap();
+    }
+
+    fn replay(pager: &Pager, clients: &mut HashMap<u32, Client>, records: &[wal::WalRecord]) {
+        for record in records {
+            apply_record(pager, &mut [clients], record.lsn, &record.record).unwrap();
+        }
+    }
+
+    // Saldo, length of the ledger and open holds of a client.
+    type State = (i64, u64, Vec<(u64, i64)>);
+
+    fn state(clients: &HashMap<u32, Client>) -> Vec<State> {
+        [1, 2]
+            .iter()
+            .map(|id| {
+                let client = &clients[id];
+                (client.saldo, client.ledger.len(), client.holds())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replays_both_sides_of_a_transfer() {
+        let path = wal_path("transfer");
+        crash(&path);
+        let (pager, mut clients) = checkpointed();
+        let (mut wal, records) = Wal::open(&path, 0).unwrap();
+        assert_eq!(records.len(), 4);
+        replay(&pager, &mut clients, &records);
+        assert_eq!(state(&clients), [(-30, 1, vec![]), (25, 2, vec![(2, 20)])]);
+        let (debit, link) = clients[&1].ledger.read(&pager, 1).unwrap();
+        assert_eq!(({ debit.value }, { link.transferencia }), (-30, 2));
+        let (credit, link) = clients[&2].ledger.read(&pager, 1).unwrap();
+        assert_eq!(({ credit.value }, { link.transferencia }), (30, 1));
+
+        // The idempotency key of the payer came back with the record.
+        let [payer, payee] = clients.get_disjoint_mut([&1, &2]);
+        let ret = payer
+            .unwrap()
+            .push_transferencia(payee.unwrap(), &mut wal, transacao(30), KEY)
+            .unwrap();
+        assert_eq!({ ret.saldo }, -30);
+        assert_eq!(wal.nrecords(), 4);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replaying_again_applies_nothing_twice() {
+        let path = wal_path("twice");
+        crash(&path);
+        let (pager, mut clients) = checkpointed();
+        let (_, records) = Wal::open(&path, 0).unwrap();
+        replay(&pager, &mut clients, &records);
+        let replayed = state(&clients);
+
+        // A crash during the replay: it starts over on clients that already have some of it.
+        replay(&pager, &mut clients, &records);
+        assert_eq!(state(&clients), replayed);
+        replay(&pager, &mut clients, &records[2..]);
+        assert_eq!(state(&clients), replayed);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replays_the_side_not_checkpointed() {
+        let path = wal_path("side");
+        crash(&path);
+        let (pager, mut clients) = checkpointed();
+        let (_, records) = Wal::open(&path, 0).unwrap();
+
+        // The payer reached its page before the crash, the payee did not.
+        let transfer = &records[0];
+        let link = Link {
+            transferencia: 2,
+            ..Link::default()
+        };
+        let debit = transfer.record.transacao;
+        let payer = clients.get_mut(&1).unwrap();
+        payer
+            .apply_transacao(&pager, transfer.lsn, debit, KEY, link)
+            .unwrap();
+
+        replay(&pager, &mut clients, &records[..1]);
+        assert_eq!(state(&clients), [(-30, 1, vec![]), (30, 1, vec![])]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,232 @@
+use crate::client::LoadError;
+use crate::crc::crc32;
+use crate::storage::{self, Storage, StorageKind};
+use std::io;
+use std::mem::size_of;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+pub const DATA_FILE: &str = "database.db";
+pub const PAGE_SIZE: usize = 4096;
+pub const FORMAT_MAGIC: [u8; 4] = *b"RPQD";
+pub const FORMAT_VERSION: u16 = 10;
+
+// Page 0 holds the `FileHeader`, page 1 is the first directory page.
+// Every client owns two slot pages, written alternately so one of them is always intact,
+// and a chain of ledger pages. Pages are never freed.
+const FIRST_DIRECTORY_PAGE: u32 = 1;
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct FileHeader {
+    magic: [u8; 4],
+    version: u16,
+    page_size: u32,
+    crc: u32,
+}
+const SIZE_FILE_HEADER: usize = size_of::<FileHeader>();
+
+impl FileHeader {
+    fn checksum(&self) -> u32 {
+        let buff: &[u8; SIZE_FILE_HEADER] = unsafe { std::mem::transmute(self) };
+        crc32(&buff[..SIZE_FILE_HEADER - size_of::<u32>()])
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct DirectoryEntry {
+    crc: u32,
+    pub id: u32,
+    pub slots: [u32; 2],
+    pub ledger: u32,
+}
+const SIZE_DIRECTORY_ENTRY: usize = size_of::<DirectoryEntry>();
+
+// Directory and ledger pages start with the number of the next page of the chain, 0 at the end.
+pub const SIZE_PAGE_LINK: usize = size_of::<u32>();
+const ENTRIES_PER_DIRECTORY_PAGE: usize = (PAGE_SIZE - SIZE_PAGE_LINK) / SIZE_DIRECTORY_ENTRY;
+
+impl DirectoryEntry {
+    fn checksum(&self) -> u32 {
+        let buff: &[u8; SIZE_DIRECTORY_ENTRY] = unsafe { std::mem::transmute(self) };
+        crc32(&buff[size_of::<u32>()..])
+    }
+}
+
+// Shared by every worker: pages are read and written with positioned I/O,
+// only allocation and the directory need synchronization.
+pub struct Pager {
+    storage: Box<dyn Storage>,
+    npages: AtomicU32,
+    directory: Mutex<Directory>,
+}
+
+struct Directory {
+    pages: Vec<u32>,
+    entries: Vec<DirectoryEntry>,
+}
+
+impl Pager {
+    pub fn open(path: &Path, kind: StorageKind) -> Result<Pager, LoadError> {
+        let storage = storage::open(kind, path);
+        if let Err(e) = storage {
+            return Err(LoadError::Io(e));
+        }
+        let storage = storage.unwrap();
+        let len = storage.size().map_err(LoadError::Io)?;
+        // A crash while allocating may leave a partial page at the end, it is reused.
+        let npages = (len / PAGE_SIZE as u64) as u32;
+
+        if npages == 0 {
+            println!("DB.Pager: Creating new DB in {}", path.display());
+            let pager = Pager {
+                storage,
+                npages: AtomicU32::new(0),
+                directory: Mutex::new(Directory {
+                    pages: vec![FIRST_DIRECTORY_PAGE],
+                    entries: Vec::new(),
+                }),
+            };
+            let mut header = FileHeader {
+                magic: FORMAT_MAGIC,
+                version: FORMAT_VERSION,
+                page_size: PAGE_SIZE as u32,
+                crc: 0,
+            };
+            header.crc = header.checksum();
+            let header: &[u8; SIZE_FILE_HEADER] = unsafe { std::mem::transmute(&header) };
+            pager.allocate().map_err(LoadError::Io)?;
+            pager.allocate().map_err(LoadError::Io)?;
+            pager.write_at(0, 0, header).map_err(LoadError::Io)?;
+            pager.sync().map_err(LoadError::Io)?;
+            return Ok(pager);
+        }
+
+        let mut header = [0; SIZE_FILE_HEADER];
+        storage.read_at(&mut header, 0).map_err(LoadError::Io)?;
+        let header: FileHeader = unsafe { std::mem::transmute(header) };
+        if header.magic != FORMAT_MAGIC {
+            return Err(LoadError::BadMagic(header.magic));
+        }
+        if header.version != FORMAT_VERSION {
+            return Err(LoadError::UnsupportedVersion(header.version));
+        }
+        if header.crc != header.checksum() {
+            return Err(LoadError::BadChecksum {
+                expected: header.crc,
+                found: header.checksum(),
+            });
+        }
+        if header.page_size != PAGE_SIZE as u32 {
+            return Err(LoadError::Invalid("page size does not match PAGE_SIZE"));
+        }
+
+        let pager = Pager {
+            storage,
+            npages: AtomicU32::new(npages),
+            directory: Mutex::new(Directory {
+                pages: Vec::new(),
+                entries: Vec::new(),
+            }),
+        };
+        let mut directory = Directory {
+            pages: Vec::new(),
+            entries: Vec::new(),
+        };
+        let mut page = FIRST_DIRECTORY_PAGE;
+        loop {
+            if page >= npages {
+                return Err(LoadError::Invalid(
+                    "directory points past the end of the file",
+                ));
+            }
+            let buff = pager.read_page(page).map_err(LoadError::Io)?;
+            directory.pages.push(page);
+            let entries = buff[SIZE_PAGE_LINK..].chunks_exact(SIZE_DIRECTORY_ENTRY);
+            for chunk in entries.take(ENTRIES_PER_DIRECTORY_PAGE) {
+                let entry: DirectoryEntry = unsafe { std::ptr::read(chunk.as_ptr() as *const _) };
+                if entry.crc != entry.checksum() {
+                    break;
+                }
+                directory.entries.push(entry);
+            }
+            let next = u32::from_ne_bytes(buff[..SIZE_PAGE_LINK].try_into().unwrap());
+            if next == 0
+                || directory.entries.len() < directory.pages.len() * ENTRIES_PER_DIRECTORY_PAGE
+            {
+                break;
+            }
+            page = next;
+        }
+        println!(
+            "DB.Pager: {} pages and {} clients loaded from {}",
+            npages,
+            directory.entries.len(),
+            path.display()
+        );
+        *pager.directory.lock().unwrap() = directory;
+        Ok(pager)
+    }
+
+    pub fn entries(&self) -> Vec<DirectoryEntry> {
+        self.directory.lock().unwrap().entries.clone()
+    }
+
+    // Appends a zeroed page to the file.
+    pub fn allocate(&self) -> io::Result<u32> {
+        let page = self.npages.fetch_add(1, Ordering::SeqCst);
+        self.storage
+            .write_at(&[0; PAGE_SIZE], page as u64 * PAGE_SIZE as u64)?;
+        Ok(page)
+    }
+
+    // The pages of the entry must already be durable, otherwise a crash could leave
+    // the directory pointing to garbage.
+    pub fn add_entry(&self, id: u32, slots: [u32; 2], ledger: u32) -> io::Result<()> {
+        let mut directory = self.directory.lock().unwrap();
+        let indx = directory.entries.len() % ENTRIES_PER_DIRECTORY_PAGE;
+        if indx == 0 && !directory.entries.is_empty() {
+            let page = self.allocate()?;
+            let last = *directory.pages.last().unwrap();
+            self.write_at(last, 0, &page.to_ne_bytes())?;
+            directory.pages.push(page);
+        }
+        let mut entry = DirectoryEntry {
+            crc: 0,
+            id,
+            slots,
+            ledger,
+        };
+        entry.crc = entry.checksum();
+        let buff: &[u8; SIZE_DIRECTORY_ENTRY] = unsafe { std::mem::transmute(&entry) };
+        let page = *directory.pages.last().unwrap();
+        self.write_at(page, SIZE_PAGE_LINK + indx * SIZE_DIRECTORY_ENTRY, buff)?;
+        directory.entries.push(entry);
+        Ok(())
+    }
+
+    pub fn read_page(&self, page: u32) -> io::Result<[u8; PAGE_SIZE]> {
+        let mut buff = [0; PAGE_SIZE];
+        self.read_at(page, 0, &mut buff)?;
+        Ok(buff)
+    }
+
+    pub fn read_at(&self, page: u32, offset: usize, buff: &mut [u8]) -> io::Result<()> {
+        assert!(offset + buff.len() <= PAGE_SIZE);
+        self.storage
+            .read_at(buff, page as u64 * PAGE_SIZE as u64 + offset as u64)
+    }
+
+    // Only the bytes in `buff` are written, the rest of the page is left untouched.
+    pub fn write_at(&self, page: u32, offset: usize, buff: &[u8]) -> io::Result<()> {
+        assert!(offset + buff.len() <= PAGE_SIZE);
+        self.storage
+            .write_at(buff, page as u64 * PAGE_SIZE as u64 + offset as u64)
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        self.storage.sync()
+    }
+}
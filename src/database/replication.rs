@@ -0,0 +1,328 @@
+use crate::apply_record;
+use crate::client::{Client, ClientRaw};
+use crate::crc::crc32;
+use crate::wal::{Record, RecordKind, RecordRaw, WalRecord, CHECKPOINT_INTERVAL};
+use crate::worker::Shared;
+use database::SIZE_OPERATION;
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+// Records kept by the primary to resend to a replica that missed some.
+// A replica further behind than this gets a snapshot of every client instead.
+pub const REPLICATION_BACKLOG: usize = 4096;
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+const MESSAGE_MAGIC: [u8; 4] = *b"RPQR";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u32)]
+enum MessageKind {
+    Record,
+    Heartbeat,
+    CatchUp,
+    Snapshot,
+    SnapshotEnd,
+}
+
+impl MessageKind {
+    fn from_u32(kind: u32) -> Option<MessageKind> {
+        Some(match kind {
+            0 => MessageKind::Record,
+            1 => MessageKind::Heartbeat,
+            2 => MessageKind::CatchUp,
+            3 => MessageKind::Snapshot,
+            4 => MessageKind::SnapshotEnd,
+            _ => return None,
+        })
+    }
+}
+
+// Replication traffic shares the socket with the operations, the size and the magic
+// tell them apart. `lsn` is the LSN of `record` for `Record`, the newest LSN of the
+// primary for `Heartbeat` and `SnapshotEnd`, and the first missing LSN for `CatchUp`.
+// Every field is an integer, so any datagram can be read as a `Message`: the CRC and
+// `kind` are checked first, `record` and `client` when they become typed values.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct Message {
+    magic: [u8; 4],
+    crc: u32,
+    kind: u32,
+    lsn: u64,
+    // Number of `Snapshot` messages before a `SnapshotEnd`.
+    nclients: u32,
+    record: RecordRaw,
+    client: ClientRaw,
+}
+pub const SIZE_MESSAGE: usize = size_of::<Message>();
+// Also lets the DB receive into a `SIZE_MESSAGE` buffer whatever comes in.
+const _: () = assert!(SIZE_MESSAGE > SIZE_OPERATION);
+
+impl Message {
+    fn new(kind: MessageKind, lsn: u64) -> Message {
+        Message {
+            magic: MESSAGE_MAGIC,
+            kind: kind as u32,
+            lsn,
+            ..unsafe { std::mem::zeroed() }
+        }
+    }
+
+    // Everything after the magic and the CRC itself.
+    fn checksum(&self) -> u32 {
+        let buff: &[u8; SIZE_MESSAGE] = unsafe { std::mem::transmute(self) };
+        crc32(&buff[2 * size_of::<u32>()..])
+    }
+
+    fn from_buffer(buff: &[u8]) -> Option<(MessageKind, Message)> {
+        if buff.len() != SIZE_MESSAGE || buff[..4] != MESSAGE_MAGIC {
+            return None;
+        }
+        let message: Message = unsafe { std::ptr::read(buff.as_ptr() as *const Message) };
+        if message.crc != message.checksum() {
+            return None;
+        }
+        Some((MessageKind::from_u32(message.kind)?, message))
+    }
+
+    fn send(&self, socket: &UdpSocket, addr: &SocketAddr) {
+        let mut message = *self;
+        message.crc = message.checksum();
+        let buff: &[u8; SIZE_MESSAGE] = unsafe { std::mem::transmute(&message) };
+        if let Err(e) = socket.send_to(buff, addr) {
+            eprintln!("DB.Replication: Could not send to {}: {}", addr, e);
+        }
+    }
+}
+
+pub fn is_message(buff: &[u8]) -> bool {
+    buff.len() == SIZE_MESSAGE && buff[..4] == MESSAGE_MAGIC
+}
+
+// Streams every WAL record to the replicas as soon as it is durable.
+pub struct Primary {
+    replicas: Vec<SocketAddr>,
+    backlog: VecDeque<WalRecord>,
+    last_heartbeat: Instant,
+}
+
+impl Primary {
+    pub fn new(replicas: Vec<SocketAddr>) -> Primary {
+        Primary {
+            replicas,
+            backlog: VecDeque::with_capacity(REPLICATION_BACKLOG),
+            last_heartbeat: Instant::now(),
+        }
+    }
+
+    pub fn publish(&mut self, socket: &UdpSocket, records: Vec<WalRecord>) {
+        if self.replicas.is_empty() {
+            return;
+        }
+        for record in records {
+            let mut message = Message::new(MessageKind::Record, record.lsn);
+            message.record = (&record.record).into();
+            for replica in self.replicas.iter() {
+                message.send(socket, replica);
+            }
+            if self.backlog.len() == REPLICATION_BACKLOG {
+                self.backlog.pop_front();
+            }
+            self.backlog.push_back(record);
+        }
+    }
+
+    // Lets idle replicas notice they lost the last records.
+    pub fn heartbeat(&mut self, socket: &UdpSocket, last_lsn: u64) {
+        if self.last_heartbeat.elapsed() < HEARTBEAT_INTERVAL {
+            return;
+        }
+        let message = Message::new(MessageKind::Heartbeat, last_lsn);
+        for replica in self.replicas.iter() {
+            message.send(socket, replica);
+        }
+        self.last_heartbeat = Instant::now();
+    }
+
+    pub fn handle(
+        &mut self,
+        socket: &UdpSocket,
+        addr: &SocketAddr,
+        buff: &[u8],
+        clients: &[&Client],
+        last_lsn: u64,
+    ) {
+        // Only replicas are listened to, whatever the datagram holds.
+        if !self.replicas.contains(addr) {
+            eprintln!("DB.Replication: Ignoring message from {}", addr);
+            return;
+        }
+        let message = Message::from_buffer(buff);
+        if message.is_none() {
+            eprintln!("DB.Replication: Ignoring invalid message from {}", addr);
+            return;
+        }
+        let (kind, message) = message.unwrap();
+        if kind != MessageKind::CatchUp {
+            return;
+        }
+        let from = message.lsn;
+        // A new replica asks from LSN 1, it still needs the clients that were never logged.
+        if from > last_lsn && from > 1 {
+            return;
+        }
+        let oldest = self.backlog.front().map(|record| record.lsn);
+        if oldest.is_some() && oldest.unwrap() <= from {
+            for record in self.backlog.iter().filter(|record| record.lsn >= from) {
+                let mut message = Message::new(MessageKind::Record, record.lsn);
+                message.record = (&record.record).into();
+                message.send(socket, addr);
+            }
+            return;
+        }
+
+        println!(
+            "DB.Replication: Sending a snapshot to {} (from LSN {})",
+            addr, from
+        );
+        for &client in clients.iter() {
+            let mut message = Message::new(MessageKind::Snapshot, client.lsn);
+            message.client = client.into();
+            message.send(socket, addr);
+        }
+        let mut message = Message::new(MessageKind::SnapshotEnd, last_lsn);
+        message.nclients = clients.len() as u32;
+        message.send(socket, addr);
+    }
+}
+
+// Applies the stream of the primary in LSN order. On a gap it asks the primary
+// to resend from the first missing record and drops everything until then.
+pub struct Follower {
+    primary: SocketAddr,
+    applied: u64,
+    last_request: Option<Instant>,
+    snapshot_clients: u32,
+}
+
+impl Follower {
+    pub fn new(primary: SocketAddr, applied: u64) -> Follower {
+        Follower {
+            primary,
+            applied,
+            last_request: None,
+            snapshot_clients: 0,
+        }
+    }
+
+    // At most one request per `HEARTBEAT_INTERVAL`, the answer may be on its way.
+    pub fn catch_up(&mut self, socket: &UdpSocket) {
+        if self
+            .last_request
+            .is_some_and(|last| last.elapsed() < HEARTBEAT_INTERVAL)
+        {
+            return;
+        }
+        Message::new(MessageKind::CatchUp, self.applied + 1).send(socket, &self.primary);
+        self.last_request = Some(Instant::now());
+        self.snapshot_clients = 0;
+    }
+
+    pub fn handle(
+        &mut self,
+        socket: &UdpSocket,
+        addr: &SocketAddr,
+        buff: &[u8],
+        shared: &Shared,
+    ) -> std::io::Result<()> {
+        // Only the primary is listened to, whatever the datagram holds.
+        if *addr != self.primary {
+            eprintln!("DB.Replication: Ignoring message from {}", addr);
+            return Ok(());
+        }
+        let message = Message::from_buffer(buff);
+        if message.is_none() {
+            eprintln!("DB.Replication: Ignoring invalid message from {}", addr);
+            return Ok(());
+        }
+        let (kind, message) = message.unwrap();
+        let lsn = message.lsn;
+        match kind {
+            MessageKind::Record => {
+                if lsn <= self.applied {
+                    return Ok(());
+                }
+                if lsn != self.applied + 1 {
+                    self.catch_up(socket);
+                    return Ok(());
+                }
+                let record = Record::try_from(&{ message.record });
+                if let Err(e) = record {
+                    eprintln!("DB.Replication: Invalid record {}: {}", lsn, e);
+                    return Ok(());
+                }
+                let record = record.unwrap();
+                let mut ids = vec![record.id];
+                if { record.kind } == RecordKind::Transferencia {
+                    ids.push(record.para);
+                }
+                let mut shards = shared.lock_shards(&ids);
+                let mut log = shared.log.lock().unwrap();
+                log.wal.append_at(lsn, &record)?;
+                if log.wal.nrecords() >= CHECKPOINT_INTERVAL {
+                    shared.checkpoint_wanted.store(true, Ordering::Relaxed);
+                }
+                drop(log);
+                let mut clients: Vec<_> = shards.iter_mut().map(|shard| &mut **shard).collect();
+                apply_record(&shared.pager, &mut clients, lsn, &record)?;
+                self.applied = lsn;
+            }
+            MessageKind::Heartbeat => {
+                if lsn > self.applied {
+                    self.catch_up(socket);
+                }
+            }
+            MessageKind::CatchUp => {}
+            MessageKind::Snapshot => {
+                let raw = message.client;
+                let id = raw.id();
+                let mut clients = shared.lock_shard(id);
+                let new = !clients.contains_key(&id);
+                if new {
+                    let client = Client::new(&shared.pager, id, 0, 0, 0)?;
+                    clients.insert(id, client);
+                }
+                let client = clients.get_mut(&id).unwrap();
+                if new || raw.lsn() > client.lsn {
+                    if let Err(e) = client.restore(&raw) {
+                        eprintln!("DB.Replication: Invalid snapshot of client {}: {}", id, e);
+                        return Ok(());
+                    }
+                }
+                self.snapshot_clients += 1;
+            }
+            MessageKind::SnapshotEnd => {
+                if self.snapshot_clients != message.nclients {
+                    eprintln!(
+                        "DB.Replication: Incomplete snapshot ({} of {} clients), retrying",
+                        self.snapshot_clients,
+                        { message.nclients }
+                    );
+                    self.last_request = None;
+                    self.catch_up(socket);
+                    return Ok(());
+                }
+                shared.checkpoint()?;
+                self.applied = self.applied.max(lsn);
+                self.last_request = None;
+                println!(
+                    "DB.Replication: Snapshot applied up to LSN {}",
+                    self.applied
+                );
+            }
+        }
+        Ok(())
+    }
+}
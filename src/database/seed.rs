@@ -0,0 +1,112 @@
+use crate::client::Client;
+use crate::pager::Pager;
+use crate::wal::{Record, Wal};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, ErrorKind};
+
+#[derive(Debug, Deserialize)]
+pub struct SeedClient {
+    pub id: u32,
+    pub limite: i64,
+    #[serde(default)]
+    pub saldo: i64,
+}
+
+fn invalid(path: &str, reason: String) -> io::Error {
+    eprintln!("DB.Seed: Invalid seed file {path}: {reason}");
+    io::Error::new(ErrorKind::InvalidData, reason)
+}
+
+pub fn read_seed(path: &str) -> io::Result<Vec<SeedClient>> {
+    let buff = std::fs::read_to_string(path);
+    if let Err(e) = buff {
+        eprintln!("DB.Seed: Could not read {path}: {e}");
+        return Err(e);
+    }
+    let seed = serde_json::from_str::<Vec<SeedClient>>(&buff.unwrap());
+    if let Err(e) = seed {
+        return Err(invalid(path, e.to_string()));
+    }
+    let seed = seed.unwrap();
+
+    let mut ids = HashSet::new();
+    for client in seed.iter() {
+        if client.id == 0 {
+            return Err(invalid(path, "id 0 is reserved".into()));
+        }
+        if !ids.insert(client.id) {
+            return Err(invalid(path, format!("id {} is repeated", client.id)));
+        }
+        if client.limite < 0 {
+            return Err(invalid(
+                path,
+                format!("client {} has a negative limite", client.id),
+            ));
+        }
+        if client.saldo < -client.limite {
+            return Err(invalid(
+                path,
+                format!("client {} has saldo under its limite", client.id),
+            ));
+        }
+    }
+    Ok(seed)
+}
+
+// Creates the clients missing from the database, new clients are logged so replicas
+// get them too. Existing ones must have been created as the seed says: their limite and
+// saldo change at runtime, so what is compared is the limite they were created with and
+// the saldo before their first transaction. A mismatch means the seed is not the one
+// of this database and refuses to start, unless `accept_drift` only reports it.
+pub fn apply_seed(
+    pager: &Pager,
+    clients: &mut HashMap<u32, Client>,
+    wal: &mut Wal,
+    seed: &[SeedClient],
+    accept_drift: bool,
+) -> io::Result<()> {
+    let mut drift = 0;
+    for seed_client in seed.iter() {
+        match clients.get(&seed_client.id) {
+            Some(client) => {
+                if client.limite_inicial != seed_client.limite
+                    || client.saldo_inicial != seed_client.saldo
+                {
+                    eprintln!(
+                        "DB.Seed: Client {} was created with limite {} and saldo {} but the seed says limite {} and saldo {}",
+                        client.id,
+                        client.limite_inicial,
+                        client.saldo_inicial,
+                        seed_client.limite,
+                        seed_client.saldo
+                    );
+                    drift += 1;
+                }
+            }
+            None => {
+                let lsn = wal.append(&Record::create_client(
+                    seed_client.id,
+                    seed_client.limite,
+                    seed_client.saldo,
+                ))?;
+                let client = Client::new(
+                    pager,
+                    seed_client.id,
+                    seed_client.limite,
+                    seed_client.saldo,
+                    lsn,
+                )?;
+                clients.insert(seed_client.id, client);
+            }
+        }
+    }
+    if drift > 0 && !accept_drift {
+        eprintln!("DB.Seed: {drift} clients differ from the seed, start with --accept-seed-drift to serve anyway");
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{drift} clients differ from the seed"),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,176 @@
+use memmap2::MmapMut;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::RwLock;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StorageKind {
+    File,
+    // Not durable: a restart starts empty. It has no WAL or audit file either, so the
+    // data directory of a file database is neither replayed nor written to.
+    Memory,
+    Mmap,
+}
+
+// Bytes the `Pager` keeps its pages in. Writing past the end grows the storage.
+pub trait Storage: Send + Sync {
+    fn size(&self) -> io::Result<u64>;
+    fn read_at(&self, buff: &mut [u8], offset: u64) -> io::Result<()>;
+    fn write_at(&self, buff: &[u8], offset: u64) -> io::Result<()>;
+    // Once it returns, everything written so far survives a crash.
+    fn sync(&self) -> io::Result<()>;
+}
+
+pub fn open(kind: StorageKind, path: &Path) -> io::Result<Box<dyn Storage>> {
+    match kind {
+        StorageKind::File => Ok(Box::new(FileStorage::open(path)?)),
+        StorageKind::Memory => Ok(Box::new(MemoryStorage::default())),
+        StorageKind::Mmap => Ok(Box::new(MmapStorage::open(path)?)),
+    }
+}
+
+fn open_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+fn out_of_bounds() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "read past the end of the storage",
+    )
+}
+
+pub struct FileStorage {
+    file: File,
+}
+
+impl FileStorage {
+    pub fn open(path: &Path) -> io::Result<FileStorage> {
+        Ok(FileStorage {
+            file: open_file(path)?,
+        })
+    }
+}
+
+impl Storage for FileStorage {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn read_at(&self, buff: &mut [u8], offset: u64) -> io::Result<()> {
+        self.file.read_exact_at(buff, offset)
+    }
+
+    fn write_at(&self, buff: &[u8], offset: u64) -> io::Result<()> {
+        self.file.write_all_at(buff, offset)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    bytes: RwLock<Vec<u8>>,
+}
+
+impl Storage for MemoryStorage {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.bytes.read().unwrap().len() as u64)
+    }
+
+    fn read_at(&self, buff: &mut [u8], offset: u64) -> io::Result<()> {
+        let bytes = self.bytes.read().unwrap();
+        let start = offset as usize;
+        if start + buff.len() > bytes.len() {
+            return Err(out_of_bounds());
+        }
+        buff.copy_from_slice(&bytes[start..start + buff.len()]);
+        Ok(())
+    }
+
+    fn write_at(&self, buff: &[u8], offset: u64) -> io::Result<()> {
+        let mut bytes = self.bytes.write().unwrap();
+        let start = offset as usize;
+        if start + buff.len() > bytes.len() {
+            bytes.resize(start + buff.len(), 0);
+        }
+        bytes[start..start + buff.len()].copy_from_slice(buff);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The file is mapped whole and remapped every time it grows.
+pub struct MmapStorage {
+    file: File,
+    // `None` while the file is empty, an empty file cannot be mapped.
+    map: RwLock<Option<MmapMut>>,
+}
+
+impl MmapStorage {
+    pub fn open(path: &Path) -> io::Result<MmapStorage> {
+        let file = open_file(path)?;
+        let map = if file.metadata()?.len() == 0 {
+            None
+        } else {
+            Some(unsafe { MmapMut::map_mut(&file)? })
+        };
+        Ok(MmapStorage {
+            file,
+            map: RwLock::new(map),
+        })
+    }
+}
+
+fn mapped_len(map: &Option<MmapMut>) -> usize {
+    map.as_ref().map_or(0, |map| map.len())
+}
+
+impl Storage for MmapStorage {
+    fn size(&self) -> io::Result<u64> {
+        Ok(mapped_len(&self.map.read().unwrap()) as u64)
+    }
+
+    fn read_at(&self, buff: &mut [u8], offset: u64) -> io::Result<()> {
+        let map = self.map.read().unwrap();
+        let start = offset as usize;
+        if start + buff.len() > mapped_len(&map) {
+            return Err(out_of_bounds());
+        }
+        buff.copy_from_slice(&map.as_ref().unwrap()[start..start + buff.len()]);
+        Ok(())
+    }
+
+    fn write_at(&self, buff: &[u8], offset: u64) -> io::Result<()> {
+        let mut map = self.map.write().unwrap();
+        let start = offset as usize;
+        let end = start + buff.len();
+        if end > mapped_len(&map) {
+            // Growing the file leaves the old mapping valid until it is replaced.
+            self.file.set_len(end as u64)?;
+            *map = Some(unsafe { MmapMut::map_mut(&self.file)? });
+        }
+        map.as_mut().unwrap()[start..end].copy_from_slice(buff);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        if let Some(map) = self.map.read().unwrap().as_ref() {
+            map.flush()?;
+        }
+        // The size of the file is not covered by the flush of the mapping.
+        self.file.sync_data()
+    }
+}
@@ -0,0 +1,635 @@
+use crate::client::{LoadError, TransacaoRaw};
+use crate::crc::crc32;
+use database::{BufferIdempotencyKey, Transacao};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::path::Path;
+
+pub const WAL_FILE: &str = "database.wal";
+pub const WAL_MAGIC: [u8; 4] = *b"RPQW";
+// Bumped whenever `RecordRaw` changes, an older log is refused instead of replayed.
+pub const WAL_VERSION: u16 = 2;
+// Number of records after which the client pages are rewritten and the log truncated.
+pub const CHECKPOINT_INTERVAL: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u32)]
+pub enum RecordKind {
+    Transacao,
+    CreateClient,
+    Transferencia,
+    UpdateLimit,
+    Hold,
+    Capture,
+    Release,
+}
+
+impl RecordKind {
+    fn from_u32(kind: u32) -> Option<RecordKind> {
+        Some(match kind {
+            0 => RecordKind::Transacao,
+            1 => RecordKind::CreateClient,
+            2 => RecordKind::Transferencia,
+            3 => RecordKind::UpdateLimit,
+            4 => RecordKind::Hold,
+            5 => RecordKind::Capture,
+            6 => RecordKind::Release,
+            _ => return None,
+        })
+    }
+}
+
+// One change to the database. Only the fields of its `kind` are meaningful:
+// `transacao`, `idempotency_key` and `estorno` for `Transacao`, `limite` and `saldo` for
+// `CreateClient`. A `Transferencia` debits `transacao` from `id`, with `idempotency_key`,
+// and credits its opposite to `para`, so both sides are in the log or neither is.
+// `UpdateLimit` sets `limite`, `transacao` is the entry it leaves in the ledger.
+// `Hold` reserves `transacao.value` as the hold `reserva`, `Capture` replaces it with
+// the debit `transacao` and `Release` drops it.
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct Record {
+    pub kind: RecordKind,
+    pub id: u32,
+    pub transacao: Transacao,
+    pub limite: i64,
+    pub saldo: i64,
+    pub idempotency_key: BufferIdempotencyKey,
+    // `seq` of the transaction a reversal undoes, 0 for a regular transaction.
+    pub estorno: u64,
+    pub para: u32,
+    pub reserva: u64,
+}
+
+impl Record {
+    pub fn transacao(
+        id: u32,
+        transacao: Transacao,
+        idempotency_key: BufferIdempotencyKey,
+    ) -> Record {
+        Record {
+            kind: RecordKind::Transacao,
+            id,
+            transacao,
+            idempotency_key,
+            ..unsafe { std::mem::zeroed() }
+        }
+    }
+    pub fn estorno(id: u32, transacao: Transacao, seq: u64) -> Record {
+        Record {
+            kind: RecordKind::Transacao,
+            id,
+            transacao,
+            estorno: seq,
+            ..unsafe { std::mem::zeroed() }
+        }
+    }
+    pub fn transferencia(
+        id: u32,
+        debit: Transacao,
+        para: u32,
+        idempotency_key: BufferIdempotencyKey,
+    ) -> Record {
+        Record {
+            kind: RecordKind::Transferencia,
+            id,
+            transacao: debit,
+            para,
+            idempotency_key,
+            ..unsafe { std::mem::zeroed() }
+        }
+    }
+    pub fn update_limit(id: u32, limite: i64, transacao: Transacao) -> Record {
+        Record {
+            kind: RecordKind::UpdateLimit,
+            id,
+            limite,
+            transacao,
+            ..unsafe { std::mem::zeroed() }
+        }
+    }
+    pub fn hold(id: u32, reserva: u64, valor: i64) -> Record {
+        let mut record = Record {
+            kind: RecordKind::Hold,
+            id,
+            reserva,
+            ..unsafe { std::mem::zeroed() }
+        };
+        record.transacao.value = valor;
+        record
+    }
+    pub fn capture(id: u32, reserva: u64, transacao: Transacao) -> Record {
+        Record {
+            kind: RecordKind::Capture,
+            id,
+            reserva,
+            transacao,
+            ..unsafe { std::mem::zeroed() }
+        }
+    }
+    pub fn release(id: u32, reserva: u64) -> Record {
+        Record {
+            kind: RecordKind::Release,
+            id,
+            reserva,
+            ..unsafe { std::mem::zeroed() }
+        }
+    }
+    pub fn create_client(id: u32, limite: i64, saldo: i64) -> Record {
+        Record {
+            kind: RecordKind::CreateClient,
+            id,
+            limite,
+            saldo,
+            ..unsafe { std::mem::zeroed() }
+        }
+    }
+}
+
+// On-disk and on-the-wire form of a `Record`, only integers like `TransacaoRaw`: any
+// bytes can be read as one, they become a `Record` once the CRC and every field are checked.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct RecordRaw {
+    kind: u32,
+    id: u32,
+    transacao: TransacaoRaw,
+    limite: i64,
+    saldo: i64,
+    idempotency_key: BufferIdempotencyKey,
+    estorno: u64,
+    para: u32,
+    reserva: u64,
+}
+
+impl From<&Record> for RecordRaw {
+    fn from(record: &Record) -> Self {
+        RecordRaw {
+            kind: record.kind as u32,
+            id: record.id,
+            transacao: (&{ record.transacao }).into(),
+            limite: record.limite,
+            saldo: record.saldo,
+            idempotency_key: record.idempotency_key,
+            estorno: record.estorno,
+            para: record.para,
+            reserva: record.reserva,
+        }
+    }
+}
+
+impl TryFrom<&RecordRaw> for Record {
+    type Error = LoadError;
+    fn try_from(raw: &RecordRaw) -> Result<Self, Self::Error> {
+        let kind = RecordKind::from_u32(raw.kind);
+        if kind.is_none() {
+            return Err(LoadError::Invalid("unknown record kind"));
+        }
+        Ok(Record {
+            kind: kind.unwrap(),
+            id: raw.id,
+            transacao: Transacao::try_from(&{ raw.transacao })?,
+            limite: raw.limite,
+            saldo: raw.saldo,
+            idempotency_key: raw.idempotency_key,
+            estorno: raw.estorno,
+            para: raw.para,
+            reserva: raw.reserva,
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct WalRecordRaw {
+    crc: u32,
+    lsn: u64,
+    record: RecordRaw,
+}
+const SIZE_WAL_RECORD: usize = size_of::<WalRecordRaw>();
+
+impl WalRecordRaw {
+    fn checksum(&self) -> u32 {
+        let buff: &[u8; SIZE_WAL_RECORD] = unsafe { std::mem::transmute(self) };
+        crc32(&buff[size_of::<u32>()..])
+    }
+}
+
+// Start of the log, the records follow it.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct WalHeader {
+    magic: [u8; 4],
+    version: u16,
+    record_size: u32,
+    crc: u32,
+}
+const SIZE_WAL_HEADER: usize = size_of::<WalHeader>();
+
+impl WalHeader {
+    fn new() -> WalHeader {
+        let mut header = WalHeader {
+            magic: WAL_MAGIC,
+            version: WAL_VERSION,
+            record_size: SIZE_WAL_RECORD as u32,
+            crc: 0,
+        };
+        header.crc = header.checksum();
+        header
+    }
+
+    fn checksum(&self) -> u32 {
+        let buff: &[u8; SIZE_WAL_HEADER] = unsafe { std::mem::transmute(self) };
+        crc32(&buff[..SIZE_WAL_HEADER - size_of::<u32>()])
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.magic != WAL_MAGIC {
+            return Err(format!("bad magic number {:?}", { self.magic }));
+        }
+        if self.version != WAL_VERSION {
+            return Err(format!("unsupported format version {}", { self.version }));
+        }
+        if self.crc != self.checksum() {
+            return Err(format!(
+                "bad checksum: expected {:#010x}, found {:#010x}",
+                { self.crc },
+                self.checksum()
+            ));
+        }
+        if self.record_size != SIZE_WAL_RECORD as u32 {
+            return Err(format!(
+                "records of {} bytes, expected {}",
+                { self.record_size },
+                SIZE_WAL_RECORD
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WalRecord {
+    pub lsn: u64,
+    pub record: Record,
+}
+
+// The intact records at the start of `buff`, the records of a log after its header, and
+// why the rest was left out.
+fn parse_records(buff: &[u8]) -> (Vec<WalRecord>, Option<String>) {
+    let mut records = Vec::new();
+    for chunk in buff.chunks(SIZE_WAL_RECORD) {
+        if chunk.len() != SIZE_WAL_RECORD {
+            return (records, Some("partial record".into()));
+        }
+        let raw: WalRecordRaw = unsafe { std::ptr::read(chunk.as_ptr() as *const _) };
+        if raw.crc != raw.checksum() {
+            return (records, Some("corrupted record".into()));
+        }
+        let record = Record::try_from(&{ raw.record });
+        if let Err(e) = record {
+            return (records, Some(format!("invalid record ({e})")));
+        }
+        records.push(WalRecord {
+            lsn: raw.lsn,
+            record: record.unwrap(),
+        });
+    }
+    (records, None)
+}
+
+// What `Wal::open` would refuse or cut off, without touching the log.
+pub fn check(path: &Path) -> io::Result<Vec<String>> {
+    let buff = match std::fs::read(path) {
+        Ok(buff) => buff,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    if buff.is_empty() {
+        return Ok(Vec::new());
+    }
+    if buff.len() < SIZE_WAL_HEADER {
+        return Ok(vec![format!(
+            "{} bytes, shorter than its header",
+            buff.len()
+        )]);
+    }
+    let header: WalHeader = unsafe { std::ptr::read(buff.as_ptr() as *const _) };
+    if let Err(e) = header.check() {
+        return Ok(vec![e]);
+    }
+    let (records, cut) = parse_records(&buff[SIZE_WAL_HEADER..]);
+    Ok(cut
+        .map(|reason| {
+            format!(
+                "{reason} after {} records, the next start cuts it and everything after it",
+                records.len()
+            )
+        })
+        .into_iter()
+        .collect())
+}
+
+pub struct Wal {
+    // None for `StorageKind::Memory`: the pages go with the process, a log could only
+    // replay records into clients that are gone.
+    file: Option<File>,
+    next_lsn: u64,
+    nrecords: usize,
+    // Written but not flushed yet.
+    pending: Vec<u8>,
+    // Records appended since the last `take_appended`.
+    appended: Vec<WalRecord>,
+}
+
+impl Wal {
+    // Opens (or creates) the log and returns every intact record in it.
+    // A torn or corrupted tail, left by a crash in the middle of an append, is cut off.
+    // A log with another header is refused, its records cannot be read by this version.
+    // `last_lsn` is the newest LSN already persisted elsewhere, so LSNs keep growing after a truncate.
+    pub fn open(path: &Path, last_lsn: u64) -> io::Result<(Wal, Vec<WalRecord>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut buff = Vec::new();
+        file.read_to_end(&mut buff)?;
+
+        // Shorter than a header it holds no record, it was just created.
+        if buff.len() < SIZE_WAL_HEADER {
+            let header = WalHeader::new();
+            let header: &[u8; SIZE_WAL_HEADER] = unsafe { std::mem::transmute(&header) };
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(header)?;
+            file.sync_all()?;
+            buff = header.to_vec();
+        }
+        let header: WalHeader = unsafe { std::ptr::read(buff.as_ptr() as *const _) };
+        if let Err(e) = header.check() {
+            eprintln!(
+                "DB.Wal: Refusing {}: {}, it was written by another version",
+                path.display(),
+                e
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        let buff = &buff[SIZE_WAL_HEADER..];
+
+        let (records, cut) = parse_records(buff);
+        if let Some(reason) = cut {
+            eprintln!(
+                "DB.Wal: Ignoring {} at the end of {}",
+                reason,
+                path.display()
+            );
+        }
+        let next_lsn = records.iter().fold(last_lsn + 1, |next_lsn, record| {
+            next_lsn.max(record.lsn + 1)
+        });
+
+        let valid_len = (SIZE_WAL_HEADER + records.len() * SIZE_WAL_RECORD) as u64;
+        if valid_len != (SIZE_WAL_HEADER + buff.len()) as u64 {
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(valid_len))?;
+        println!(
+            "DB.Wal: {} records loaded from {}",
+            records.len(),
+            path.display()
+        );
+
+        let nrecords = records.len();
+        Ok((
+            Wal {
+                file: Some(file),
+                next_lsn,
+                nrecords,
+                pending: Vec::new(),
+                appended: Vec::new(),
+            },
+            records,
+        ))
+    }
+
+    // A log that keeps nothing, the records only reach the replicas.
+    pub fn memory(last_lsn: u64) -> Wal {
+        Wal {
+            file: None,
+            next_lsn: last_lsn + 1,
+            nrecords: 0,
+            pending: Vec::new(),
+            appended: Vec::new(),
+        }
+    }
+
+    // Only buffers the record, it is durable after the next `flush`.
+    pub fn write(&mut self, record: &Record) -> u64 {
+        let lsn = self.next_lsn;
+        self.write_at(lsn, record);
+        self.appended.push(WalRecord {
+            lsn,
+            record: *record,
+        });
+        lsn
+    }
+
+    fn write_at(&mut self, lsn: u64, record: &Record) {
+        let mut raw = WalRecordRaw {
+            crc: 0,
+            lsn,
+            record: record.into(),
+        };
+        raw.crc = raw.checksum();
+        let buff: &[u8; SIZE_WAL_RECORD] = unsafe { std::mem::transmute(&raw) };
+        self.pending.extend_from_slice(buff);
+        self.next_lsn = lsn + 1;
+        self.nrecords += 1;
+    }
+
+    // Makes every written record durable with a single sync.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        if let Some(file) = &mut self.file {
+            file.write_all(&self.pending)?;
+            file.sync_data()?;
+        }
+        self.pending.clear();
+        Ok(())
+    }
+
+    // Writes the record and only returns after it reached the disk.
+    pub fn append(&mut self, record: &Record) -> io::Result<u64> {
+        let lsn = self.write(record);
+        self.flush()?;
+        Ok(lsn)
+    }
+
+    // Used by replicas, which keep the LSNs of the primary.
+    pub fn append_at(&mut self, lsn: u64, record: &Record) -> io::Result<u64> {
+        self.write_at(lsn, record);
+        self.flush()?;
+        Ok(lsn)
+    }
+
+    // LSN of the newest record ever appended, including the truncated ones.
+    pub fn last_lsn(&self) -> u64 {
+        self.next_lsn - 1
+    }
+
+    pub fn take_appended(&mut self) -> Vec<WalRecord> {
+        std::mem::take(&mut self.appended)
+    }
+
+    pub fn nrecords(&self) -> usize {
+        self.nrecords
+    }
+
+    // Must only be called once every record is durable in the client files.
+    pub fn truncate(&mut self) -> io::Result<()> {
+        assert!(self.pending.is_empty());
+        if let Some(file) = &mut self.file {
+            file.set_len(SIZE_WAL_HEADER as u64)?;
+            file.seek(SeekFrom::Start(SIZE_WAL_HEADER as u64))?;
+            file.sync_all()?;
+        }
+        self.nrecords = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::NCHAR_DESCRIPTION;
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    // A fresh log in the temp dir, removed first in case a failed run left it behind.
+    fn path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("wal-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn record(id: u32, value: i64) -> Record {
+        let transacao = Transacao {
+            value,
+            transacao_description: ['\0'; NCHAR_DESCRIPTION],
+            timestap: UNIX_EPOCH + Duration::from_secs(1),
+        };
+        Record::transacao(id, transacao, [0; database::SIZE_IDEMPOTENCY_KEY])
+    }
+
+    fn values(records: &[WalRecord]) -> Vec<(u64, i64)> {
+        records
+            .iter()
+            .map(|record| (record.lsn, { record.record.transacao.value }))
+            .collect()
+    }
+
+    #[test]
+    fn replays_what_was_flushed() {
+        let path = path("replay");
+        let (mut wal, records) = Wal::open(&path, 0).unwrap();
+        assert!(records.is_empty());
+        for value in [10, -20, 30] {
+            wal.write(&record(1, value));
+        }
+        wal.flush().unwrap();
+        // Never flushed, lost by the crash.
+        wal.write(&record(1, 40));
+        drop(wal);
+
+        let (mut wal, records) = Wal::open(&path, 0).unwrap();
+        assert_eq!(values(&records), [(1, 10), (2, -20), (3, 30)]);
+        assert_eq!({ records[1].record.kind }, RecordKind::Transacao);
+        assert_eq!(wal.write(&record(1, 50)), 4);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cuts_a_torn_or_corrupted_tail() {
+        let path = path("tail");
+        let (mut wal, _) = Wal::open(&path, 0).unwrap();
+        for value in [1, 2, 3] {
+            wal.write(&record(1, value));
+        }
+        wal.flush().unwrap();
+        drop(wal);
+
+        // Half of a fourth record, as a crash in the middle of an append leaves it.
+        let mut buff = std::fs::read(&path).unwrap();
+        let whole = buff.clone();
+        buff.extend_from_slice(&whole[SIZE_WAL_HEADER..SIZE_WAL_HEADER + SIZE_WAL_RECORD / 2]);
+        std::fs::write(&path, &buff).unwrap();
+        assert_eq!(check(&path).unwrap().len(), 1);
+        let (mut wal, records) = Wal::open(&path, 0).unwrap();
+        assert_eq!(values(&records), [(1, 1), (2, 2), (3, 3)]);
+        assert!(check(&path).unwrap().is_empty());
+        wal.append(&record(1, 4)).unwrap();
+        drop(wal);
+
+        // A damaged record ends the log, the ones after it are not trusted either.
+        let mut buff = std::fs::read(&path).unwrap();
+        buff[SIZE_WAL_HEADER + SIZE_WAL_RECORD + 8] ^= 0xff;
+        std::fs::write(&path, &buff).unwrap();
+        let (_, records) = Wal::open(&path, 0).unwrap();
+        assert_eq!(values(&records), [(1, 1)]);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            (SIZE_WAL_HEADER + SIZE_WAL_RECORD) as u64
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn refuses_another_header() {
+        let path = path("header");
+        let (mut wal, _) = Wal::open(&path, 0).unwrap();
+        wal.append(&record(1, 1)).unwrap();
+        drop(wal);
+
+        let mut buff = std::fs::read(&path).unwrap();
+        buff[0] = b'X';
+        std::fs::write(&path, &buff).unwrap();
+        let err = Wal::open(&path, 0).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(check(&path).unwrap().len(), 1);
+        // Left as it was for an operator to look at.
+        assert_eq!(std::fs::read(&path).unwrap(), buff);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn lsns_keep_growing_after_a_truncate() {
+        let path = path("truncate");
+        let (mut wal, _) = Wal::open(&path, 0).unwrap();
+        wal.append(&record(1, 1)).unwrap();
+        wal.append(&record(1, 2)).unwrap();
+        wal.truncate().unwrap();
+        assert_eq!((wal.nrecords(), wal.last_lsn()), (0, 2));
+        drop(wal);
+
+        // The clients already have LSN 2, the log is empty.
+        let (mut wal, records) = Wal::open(&path, 2).unwrap();
+        assert!(records.is_empty());
+        assert_eq!(wal.append(&record(1, 3)).unwrap(), 3);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn memory_log_keeps_nothing() {
+        let mut wal = Wal::memory(7);
+        assert_eq!(wal.append(&record(1, 1)).unwrap(), 8);
+        wal.truncate().unwrap();
+        // The replicas still get what was written.
+        assert_eq!(values(&wal.take_appended()), [(8, 1)]);
+    }
+}
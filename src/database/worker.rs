@@ -0,0 +1,571 @@
+use crate::audit::AuditLog;
+use crate::client::{Client, EstornoError, HoldError, TransacaoError};
+use crate::pager::Pager;
+use crate::replication::Primary;
+use crate::wal::{Record, Wal, CHECKPOINT_INTERVAL};
+use database::codec::{encode_pagina, envelope, Encode};
+use database::{ClienteReturn, Operation, OperationKind, Status, Transacao};
+use std::collections::{HashMap, VecDeque};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// Most operations applied before a single WAL flush.
+pub const GROUP_COMMIT_MAX: usize = 256;
+// Jobs waiting for a worker, past that the router replies `Status::Busy`.
+pub const QUEUE_MAX: usize = 4 * GROUP_COMMIT_MAX;
+
+pub type Shard = Mutex<HashMap<u32, Client>>;
+
+// The WAL is shared by every worker so LSNs keep a single order, which is also
+// the order the records are streamed to the replicas.
+pub struct Log {
+    pub wal: Wal,
+    pub primary: Primary,
+}
+
+impl Log {
+    // Makes every written record durable, of any worker, and streams them to the replicas.
+    pub fn flush(&mut self, socket: &UdpSocket) -> std::io::Result<()> {
+        self.wal.flush()?;
+        self.primary.publish(socket, self.wal.take_appended());
+        Ok(())
+    }
+}
+
+// Locks are always taken in this order: shards by index, then `log`, then `audit`.
+// `replies` is only taken with none of them held.
+pub struct Shared {
+    pub pager: Pager,
+    pub log: Mutex<Log>,
+    pub audit: Mutex<AuditLog>,
+    pub replies: Mutex<Replies>,
+    shards: Vec<Shard>,
+    pub checkpoint_wanted: AtomicBool,
+    // Highest client id given out, new clients take the next one.
+    pub max_id: AtomicU32,
+}
+
+impl Shared {
+    pub fn new(
+        pager: Pager,
+        clients: HashMap<u32, Client>,
+        log: Log,
+        audit: AuditLog,
+        nshards: usize,
+    ) -> Shared {
+        let max_id = clients.keys().max().copied().unwrap_or(0);
+        let mut shards: Vec<HashMap<u32, Client>> = (0..nshards).map(|_| HashMap::new()).collect();
+        for (id, client) in clients {
+            shards[id as usize % nshards].insert(id, client);
+        }
+        Shared {
+            pager,
+            log: Mutex::new(log),
+            audit: Mutex::new(audit),
+            replies: Mutex::new(Replies::default()),
+            shards: shards.into_iter().map(Mutex::new).collect(),
+            checkpoint_wanted: AtomicBool::new(false),
+            max_id: AtomicU32::new(max_id),
+        }
+    }
+
+    pub fn shard_of(&self, id: u32) -> usize {
+        id as usize % self.shards.len()
+    }
+
+    pub fn lock_shard(&self, id: u32) -> MutexGuard<'_, HashMap<u32, Client>> {
+        self.shards[self.shard_of(id)].lock().unwrap()
+    }
+
+    // Locks the shards of `ids` in the lock order, each one once.
+    pub fn lock_shards(&self, ids: &[u32]) -> Vec<MutexGuard<'_, HashMap<u32, Client>>> {
+        let mut shards: Vec<usize> = ids.iter().map(|id| self.shard_of(*id)).collect();
+        shards.sort();
+        shards.dedup();
+        shards
+            .into_iter()
+            .map(|shard| self.shards[shard].lock().unwrap())
+            .collect()
+    }
+
+    // Workers hold their shard for a whole batch, so once every shard is locked
+    // there is no record left in the WAL buffer nor a ledger write pending.
+    pub fn lock_all(&self) -> Vec<MutexGuard<'_, HashMap<u32, Client>>> {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap())
+            .collect()
+    }
+
+    pub fn checkpoint(&self) -> std::io::Result<()> {
+        let mut shards = self.lock_all();
+        for clients in shards.iter_mut() {
+            for client in clients.values_mut() {
+                client.checkpoint(&self.pager)?;
+            }
+        }
+        self.pager.sync()?;
+        self.log.lock().unwrap().wal.truncate()
+    }
+}
+
+// Most replies remembered for retransmitted operations, the ones in flight aside.
+pub const REPLIES_MAX: usize = 4096;
+
+type RequestKey = (SocketAddr, u64);
+
+// The last replies sent, so a retransmitted operation gets the same reply instead of
+// being applied again. The router starts an entry before handing the operation to a
+// worker, the worker fills it in when it replies.
+#[derive(Default)]
+pub struct Replies {
+    // `None` while the operation is still with a worker.
+    sent: HashMap<RequestKey, Option<Vec<u8>>>,
+    // Oldest first.
+    order: VecDeque<RequestKey>,
+}
+
+pub enum Seen<'a> {
+    New,
+    InFlight,
+    Replied(&'a [u8]),
+}
+
+impl Replies {
+    pub fn seen(&self, addr: SocketAddr, request_id: u64) -> Seen<'_> {
+        match self.sent.get(&(addr, request_id)) {
+            None => Seen::New,
+            Some(None) => Seen::InFlight,
+            Some(Some(reply)) => Seen::Replied(reply),
+        }
+    }
+
+    // Evicts the oldest reply already sent. An operation still with a worker is never
+    // evicted, a retransmission would be applied twice: with every entry in flight the
+    // cache grows past `REPLIES_MAX` until they are replied.
+    pub fn start(&mut self, addr: SocketAddr, request_id: u64) {
+        if self.order.len() >= REPLIES_MAX {
+            let replied = self
+                .order
+                .iter()
+                .position(|key| matches!(self.sent.get(key), Some(Some(_))));
+            if let Some(indx) = replied {
+                let oldest = self.order.remove(indx).unwrap();
+                self.sent.remove(&oldest);
+            }
+        }
+        self.sent.insert((addr, request_id), None);
+        self.order.push_back((addr, request_id));
+    }
+
+    // The operation was not taken, a retransmission is handled as new.
+    pub fn forget(&mut self, addr: SocketAddr, request_id: u64) {
+        self.sent.remove(&(addr, request_id));
+        self.order.retain(|key| *key != (addr, request_id));
+    }
+
+    fn finish(&mut self, addr: SocketAddr, request_id: u64, reply: &[u8]) {
+        if let Some(sent) = self.sent.get_mut(&(addr, request_id)) {
+            *sent = Some(reply.to_vec());
+        }
+    }
+}
+
+pub struct Job {
+    pub addr: SocketAddr,
+    pub op: Operation,
+}
+
+// Owns the clients of one shard: takes every job already queued, applies them,
+// flushes the WAL once and only then replies.
+pub fn run(shared: Arc<Shared>, jobs: Receiver<Job>, socket: UdpSocket) {
+    while let Ok(job) = jobs.recv() {
+        let mut batch = vec![job];
+        while batch.len() < GROUP_COMMIT_MAX {
+            match jobs.try_recv() {
+                Ok(job) => batch.push(job),
+                Err(_) => break,
+            }
+        }
+
+        // A transfer also needs the shard of the payee, so it runs on its own between
+        // batches: a worker never waits for a shard while holding its own.
+        let mut rest = &batch[..];
+        while let Some(indx) = rest
+            .iter()
+            .position(|job| matches!(job.op.kind, OperationKind::Transferencia))
+        {
+            run_batch(&shared, &rest[..indx], &socket);
+            run_transferencia(&shared, &rest[indx], &socket);
+            rest = &rest[indx + 1..];
+        }
+        run_batch(&shared, rest, &socket);
+    }
+}
+
+fn run_batch(shared: &Shared, batch: &[Job], socket: &UdpSocket) {
+    if batch.is_empty() {
+        return;
+    }
+    let mut clients = shared.lock_shard(batch[0].op.id);
+    let replies: Vec<Result<Vec<u8>, Status>> = batch
+        .iter()
+        .map(|job| process_operation(&job.op, shared, &mut clients))
+        .collect();
+
+    flush(shared, socket);
+    for job in batch.iter() {
+        if let Some(client) = clients.get_mut(&{ job.op.id }) {
+            commit_ledger(shared, client);
+        }
+    }
+    drop(clients);
+
+    for (job, reply) in batch.iter().zip(replies) {
+        send_reply(shared, socket, job, &reply);
+    }
+}
+
+fn run_transferencia(shared: &Shared, job: &Job, socket: &UdpSocket) {
+    let (id, para) = (job.op.id, job.op.para);
+    let mut shards = shared.lock_shards(&[id, para]);
+    let (payer, payee) = if shards.len() == 1 {
+        let [payer, payee] = shards[0].get_disjoint_mut([&id, &para]);
+        (payer, payee)
+    } else {
+        let (low, high) = shards.split_at_mut(1);
+        if shared.shard_of(id) < shared.shard_of(para) {
+            (low[0].get_mut(&id), high[0].get_mut(&para))
+        } else {
+            (high[0].get_mut(&id), low[0].get_mut(&para))
+        }
+    };
+    if payer.is_none() || payee.is_none() {
+        eprintln!("DB: Invalid transfer from {} to {}", id, para);
+        drop(shards);
+        send_reply(shared, socket, job, &Err(Status::NotFound));
+        return;
+    }
+    let (payer, payee) = (payer.unwrap(), payee.unwrap());
+
+    let mut log = shared.log.lock().unwrap();
+    let ret = payer.push_transferencia(
+        payee,
+        &mut log.wal,
+        job.op.transacao,
+        job.op.idempotency_key,
+    );
+    drop(log);
+    if let Err(TransacaoError::KeyReused) = ret {
+        eprintln!("DB: Refusing a transfer of {} reusing a key", id);
+        drop(shards);
+        send_reply(shared, socket, job, &Err(Status::Conflict));
+        return;
+    }
+    if let Err(TransacaoError::NotPositive) = ret {
+        eprintln!(
+            "DB: Refusing a transfer of {} from {}",
+            { job.op.transacao.value },
+            id
+        );
+        drop(shards);
+        send_reply(shared, socket, job, &Err(Status::Malformed));
+        return;
+    }
+    if let Err(TransacaoError::Limit(motivo)) = ret {
+        let debit = Transacao {
+            value: -job.op.transacao.value,
+            ..job.op.transacao
+        };
+        shared.audit.lock().unwrap().write(id, &debit, motivo, para);
+        flush(shared, socket);
+        drop(shards);
+        send_reply(shared, socket, job, &Err(Status::LimitExceeded));
+        return;
+    }
+    flush(shared, socket);
+    commit_ledger(shared, payer);
+    commit_ledger(shared, payee);
+    drop(shards);
+
+    send_reply(shared, socket, job, &Ok(ret.unwrap().to_bytes()));
+}
+
+fn flush(shared: &Shared, socket: &UdpSocket) {
+    let mut log = shared.log.lock().unwrap();
+    if let Err(e) = log.flush(socket) {
+        // Nothing of the batch was acknowledged, a restart replays what reached the disk.
+        eprintln!("DB: Could not write the WAL: {}", e);
+        std::process::exit(1);
+    }
+    if log.wal.nrecords() >= CHECKPOINT_INTERVAL {
+        shared.checkpoint_wanted.store(true, Ordering::Relaxed);
+    }
+    drop(log);
+    if let Err(e) = shared.audit.lock().unwrap().flush() {
+        eprintln!("DB: Could not write the audit log: {}", e);
+        std::process::exit(1);
+    }
+}
+
+// A refusal by the limite goes to the audit log, flushed with the rest of the batch.
+fn hold_status(shared: &Shared, id: u32, e: HoldError) -> Status {
+    match e {
+        HoldError::NotFound => Status::NotFound,
+        HoldError::Full => Status::Conflict,
+        HoldError::NotPositive => Status::Malformed,
+        HoldError::Limit(motivo, transacao) => {
+            shared
+                .audit
+                .lock()
+                .unwrap()
+                .write(id, &transacao, motivo, 0);
+            Status::LimitExceeded
+        }
+    }
+}
+
+fn commit_ledger(shared: &Shared, client: &mut Client) {
+    if let Err(e) = client.commit_ledger(&shared.pager) {
+        eprintln!("DB: Could not write the ledger: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn send_reply(shared: &Shared, socket: &UdpSocket, job: &Job, reply: &Result<Vec<u8>, Status>) {
+    let (addr, request_id) = (&job.addr, job.op.request_id);
+    let reply = match reply {
+        Ok(payload) => envelope(request_id, Status::Ok, payload),
+        Err(status) => envelope(request_id, *status, &[]),
+    };
+    if request_id != 0 {
+        shared
+            .replies
+            .lock()
+            .unwrap()
+            .finish(*addr, request_id, &reply);
+    }
+    if let Err(e) = socket.send_to(&reply, addr) {
+        eprint!("DB: Could not respond to {}: {}", addr, e);
+    }
+}
+
+// Returns the payload of the reply to `op`. Writes are only in the WAL buffer, it must
+// be flushed before the reply is sent.
+fn process_operation(
+    op: &Operation,
+    shared: &Shared,
+    clients: &mut HashMap<u32, Client>,
+) -> Result<Vec<u8>, Status> {
+    let id = op.id;
+    match op.kind {
+        OperationKind::Extrato => {
+            let client = clients.get(&id);
+            if client.is_none() {
+                eprint!("DB: Invalid id {}", id);
+                return Err(Status::NotFound);
+            }
+
+            let client = client.unwrap();
+            let filtro = op.filtro;
+            let extrato = if filtro.ativo {
+                client.extrato_filtrado(&shared.pager, filtro)
+            } else {
+                Ok(client.extrato())
+            };
+            if let Err(e) = extrato {
+                eprintln!("DB: Could not read the ledger of id {}: {}", id, e);
+                return Err(Status::StorageError);
+            }
+            Ok(extrato.unwrap().to_bytes())
+        }
+        OperationKind::Transacao => {
+            let client = clients.get_mut(&id);
+            if client.is_none() {
+                eprint!("DB: Invalid id {}", id);
+                return Err(Status::NotFound);
+            }
+            let client = client.unwrap();
+            let mut log = shared.log.lock().unwrap();
+            let ret = client.push_transacao(&mut log.wal, op.transacao, op.idempotency_key);
+            drop(log);
+            match ret {
+                Ok(ret) => Ok(ret.to_bytes()),
+                Err(TransacaoError::Limit(motivo)) => {
+                    let mut audit = shared.audit.lock().unwrap();
+                    audit.write(id, &op.transacao, motivo, 0);
+                    Err(Status::LimitExceeded)
+                }
+                Err(TransacaoError::KeyReused) => {
+                    eprintln!("DB: Refusing a transaction of {} reusing a key", id);
+                    Err(Status::Conflict)
+                }
+                Err(TransacaoError::NotPositive) => Err(Status::Malformed),
+            }
+        }
+        OperationKind::Estorno => {
+            let client = clients.get_mut(&id);
+            if client.is_none() {
+                eprint!("DB: Invalid id {}", id);
+                return Err(Status::NotFound);
+            }
+            let client = client.unwrap();
+            let seq = op.estorno;
+            let mut log = shared.log.lock().unwrap();
+            let ret = client.push_estorno(&shared.pager, &mut log.wal, seq);
+            if let Err(e) = ret {
+                eprintln!("DB: Refusing estorno of {} for id {}: {}", seq, id, e);
+                return Err(match e {
+                    EstornoError::NotFound => Status::NotFound,
+                    EstornoError::Limit(motivo, transacao) => {
+                        let mut audit = shared.audit.lock().unwrap();
+                        audit.write(id, &transacao, motivo, 0);
+                        Status::LimitExceeded
+                    }
+                    EstornoError::Io(_) => Status::StorageError,
+                    EstornoError::AlreadyReversed
+                    | EstornoError::IsEstorno
+                    | EstornoError::IsTransferencia
+                    | EstornoError::IsUpdateLimit => Status::Conflict,
+                });
+            }
+            Ok(ret.unwrap().to_bytes())
+        }
+        OperationKind::UpdateLimit => {
+            let client = clients.get_mut(&id);
+            if client.is_none() {
+                eprint!("DB: Invalid id {}", id);
+                return Err(Status::NotFound);
+            }
+            let client = client.unwrap();
+            let mut log = shared.log.lock().unwrap();
+            let ret = client.push_limite(&mut log.wal, op.limite);
+            if ret.is_err() {
+                eprintln!("DB: Refusing limite {} for id {}", { op.limite }, id);
+                return Err(Status::LimitExceeded);
+            }
+            let ret = ret.unwrap();
+            let ret = ClienteReturn {
+                id,
+                limite: ret.limite,
+                saldo: ret.saldo,
+            };
+            Ok(ret.to_bytes())
+        }
+        OperationKind::Hold => {
+            let client = clients.get_mut(&id);
+            if client.is_none() {
+                eprint!("DB: Invalid id {}", id);
+                return Err(Status::NotFound);
+            }
+            let client = client.unwrap();
+            let valor = op.transacao.value;
+            let mut log = shared.log.lock().unwrap();
+            let ret = client.push_hold(&mut log.wal, valor);
+            if let Err(e) = ret {
+                eprintln!("DB: Refusing hold of {} for id {}: {}", valor, id, e);
+                return Err(hold_status(shared, id, e));
+            }
+            Ok(ret.unwrap().to_bytes())
+        }
+        OperationKind::Capture | OperationKind::Release => {
+            let client = clients.get_mut(&id);
+            if client.is_none() {
+                eprint!("DB: Invalid id {}", id);
+                return Err(Status::NotFound);
+            }
+            let client = client.unwrap();
+            let reserva = op.reserva;
+            let mut log = shared.log.lock().unwrap();
+            let ret = if matches!(op.kind, OperationKind::Capture) {
+                client.push_capture(&mut log.wal, reserva, op.transacao)
+            } else {
+                client.push_release(&mut log.wal, reserva)
+            };
+            if let Err(e) = ret {
+                eprintln!(
+                    "DB: Refusing to close hold {} for id {}: {}",
+                    reserva, id, e
+                );
+                return Err(hold_status(shared, id, e));
+            }
+            Ok(ret.unwrap().to_bytes())
+        }
+        OperationKind::Transacoes => {
+            let client = clients.get(&id);
+            if client.is_none() {
+                eprint!("DB: Invalid id {}", id);
+                return Err(Status::NotFound);
+            }
+            let client = client.unwrap();
+            let page = client.transacoes(&shared.pager, op.pagina);
+            if let Err(e) = page {
+                eprintln!("DB: Could not read the ledger of id {}: {}", id, e);
+                return Err(Status::StorageError);
+            }
+            let (header, transacoes) = page.unwrap();
+            Ok(encode_pagina(&header, &transacoes))
+        }
+        OperationKind::Saldo => {
+            let client = clients.get(&id);
+            if client.is_none() {
+                eprint!("DB: Invalid id {}", id);
+                return Err(Status::NotFound);
+            }
+            let ret = client.unwrap().saldo_em(&shared.pager, op.em);
+            if let Err(e) = ret {
+                eprintln!("DB: Could not read the ledger of id {}: {}", id, e);
+                return Err(Status::StorageError);
+            }
+            Ok(ret.unwrap().to_bytes())
+        }
+        OperationKind::Rejeicoes => {
+            if !clients.contains_key(&id) {
+                eprint!("DB: Invalid id {}", id);
+                return Err(Status::NotFound);
+            }
+            let page = shared.audit.lock().unwrap().read_before(id, op.pagina);
+            if let Err(e) = page {
+                eprintln!("DB: Could not read the audit log of id {}: {}", id, e);
+                return Err(Status::StorageError);
+            }
+            let (header, rejeicoes) = page.unwrap();
+            Ok(encode_pagina(&header, &rejeicoes))
+        }
+        // Taken out of the batches by `run`.
+        OperationKind::Transferencia => unreachable!(),
+        // The router already checked it and picked the id.
+        OperationKind::CreateClient => {
+            let novo_cliente = op.novo_cliente;
+            // Flushed right away, the client pages are written before the batch ends.
+            let record = Record::create_client(id, novo_cliente.limite, novo_cliente.saldo);
+            let lsn = shared.log.lock().unwrap().wal.append(&record);
+            if let Err(e) = lsn {
+                eprintln!("DB: Could not write the WAL for new client {}: {}", id, e);
+                std::process::exit(1);
+            }
+            let lsn = lsn.unwrap();
+            let client = Client::new(
+                &shared.pager,
+                id,
+                novo_cliente.limite,
+                novo_cliente.saldo,
+                lsn,
+            );
+            if let Err(e) = client {
+                // The WAL already has the record, a restart replays it.
+                eprintln!("DB: Could not create client {}: {}", id, e);
+                std::process::exit(1);
+            }
+            clients.insert(id, client.unwrap());
+            let ret = ClienteReturn {
+                id,
+                limite: novo_cliente.limite,
+                saldo: novo_cliente.saldo,
+            };
+            Ok(ret.to_bytes())
+        }
+    }
+}
@@ -7,6 +7,7 @@ use std::net::UdpSocket;
 use std::process::exit;
 
 const DATA_LIMIT: usize = 1024;
+const RESPONSE_LIMIT: usize = 65_507; // Largest UDP payload
 const PORT: u16 = 9999;
 
 fn main() -> std::io::Result<()> {
@@ -58,13 +59,13 @@ fn main() -> std::io::Result<()> {
         }
         round_robin_flag = !round_robin_flag;
 
-        if let Err(e) = conn2backend.send(&buf) {
+        if let Err(e) = conn2backend.send(buf) {
             eprintln!("Could not send to backend: {}", e);
             let _ = conn.write(b"HTTP/1.1 500 Internal Error\r\nReason: Send2Back\r\n\r\n");
             continue;
         };
 
-        let mut buf = [0_u8; DATA_LIMIT];
+        let mut buf = [0_u8; RESPONSE_LIMIT];
         let nbytes = conn2backend.recv(&mut buf);
         if let Err(e) = nbytes {
             eprintln!("Could not recv from backend: {}", e);